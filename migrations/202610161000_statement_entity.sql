@@ -0,0 +1,67 @@
+/* Move the entities of a statement into a separate table to allow statements with any number of entities.
+   Foreign keys follow renamed tables, so opinion and private_key are rebuilt as well. */
+alter table statement rename to statement_v1;
+alter table opinion rename to opinion_v1;
+alter table private_key rename to private_key_v1;
+
+create table statement(
+    id integer primary key,
+    name text not null,
+    content text not null unique
+);
+create table statement_entity(
+    statement_id integer not null,
+    position integer not null,
+    entity_type integer,
+    entity text not null,
+    cidr_min text,
+    cidr_max text,
+    primary key(statement_id, position),
+    foreign key(statement_id) references statement(id) on delete cascade
+);
+create table opinion(
+    id integer primary key,
+    statement_id integer not null,
+    signer_id integer not null,
+    date integer not null,
+    valid integer not null,
+    serial integer not null,
+    certainty integer not null,
+    signature text not null,
+    comment text,
+    unique(statement_id,signer_id,date,serial),
+    foreign key(statement_id) references statement(id),
+    foreign key(signer_id) references statement(id)
+);
+create table private_key(
+    signer_id integer not null,
+    key text not null,
+    foreign key(signer_id) references statement(id)
+);
+
+insert into statement(id, name, content)
+    select id, name, name || '(' || entity_1
+        || coalesce(',' || entity_2, '') || coalesce(',' || entity_3, '') || coalesce(',' || entity_4, '')
+        || ')'
+    from statement_v1;
+/* entity_type and the cidr columns of later positions are filled in by Storage::fix_statement_entities */
+insert into statement_entity(statement_id, position, entity, cidr_min, cidr_max)
+    select id, 0, entity_1, cidr_min, cidr_max from statement_v1;
+insert into statement_entity(statement_id, position, entity)
+    select id, 1, entity_2 from statement_v1 where entity_2 is not null;
+insert into statement_entity(statement_id, position, entity)
+    select id, 2, entity_3 from statement_v1 where entity_3 is not null;
+insert into statement_entity(statement_id, position, entity)
+    select id, 3, entity_4 from statement_v1 where entity_4 is not null;
+insert into opinion select * from opinion_v1;
+insert into private_key select * from private_key_v1;
+
+drop table private_key_v1;
+drop table opinion_v1;
+drop table statement_v1;
+
+create index if not exists idx_statement_name on statement(name);
+create index if not exists idx_entity on statement_entity(entity);
+create index if not exists idx_cidr_min on statement_entity(cidr_min);
+create index if not exists idx_cidr_max on statement_entity(cidr_max);
+create index if not exists idx_opinion_fk_statement on opinion(statement_id);
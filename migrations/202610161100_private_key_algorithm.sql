@@ -0,0 +1 @@
+alter table private_key add column algorithm text not null default 'secp256k1';
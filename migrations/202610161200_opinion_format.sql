@@ -0,0 +1,2 @@
+-- signature format of opinions: 1 = textual (legacy), 2 = canonical binary encoding
+alter table opinion add column format integer not null default 1;
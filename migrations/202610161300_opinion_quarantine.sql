@@ -0,0 +1,15 @@
+-- opinions whose signatures did not verify, kept for inspection.
+-- statement and signer are stored as text so that they survive cleanup of the statement table
+create table opinion_quarantine(
+    id integer primary key,
+    statement text not null,
+    signer text not null,
+    date integer not null,
+    valid integer not null,
+    serial integer not null,
+    certainty integer not null,
+    comment text,
+    signature text not null,
+    format integer not null,
+    quarantined integer not null
+);
@@ -0,0 +1,9 @@
+-- peers we have been connected to, so that they can be redialed after a restart.
+-- times are seconds since the UNIX epoch, addresses are separated by spaces
+create table peer(
+    peer_id text primary key,
+    addresses text not null default '',
+    last_seen integer not null,
+    synced_rounds integer not null default 0,
+    last_synced integer
+);
@@ -0,0 +1,168 @@
+use std::{path::PathBuf, sync::Arc};
+
+use async_std::{sync::RwLock, task::block_on};
+use futures::{channel::mpsc::Sender, SinkExt};
+use log::error;
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    validate::Validator, Context, Editor, Helper,
+};
+
+use crate::{model::EntityType, storage::Storage};
+
+mod table;
+pub use table::{OutputFormat, Table};
+
+/// Local commands (entered with a leading '!') and their description, for !help and completion
+pub const COMMANDS: &[(&str, &str)] = &[
+    ("help", "show this list"),
+    ("templates", "list the known statement templates"),
+    ("signers", "list the known signers and their trust"),
+    ("trust", "list the signers in our web of trust"),
+    ("peers", "list the peers known from this and earlier runs"),
+    (
+        "stats",
+        "show the size of the database and the number of peers",
+    ),
+    (
+        "opinions",
+        "<statement id>: list the opinions on a statement",
+    ),
+    (
+        "retract",
+        "<statement>: withdraw our opinion on a statement",
+    ),
+    (
+        "sync",
+        "[date]: announce our sync infos for a date, default today",
+    ),
+    ("catch-up", "start a catch-up round with connected peers"),
+    (
+        "verify",
+        "[quarantine]: check the signatures of all opinions",
+    ),
+    ("normalize", "rewrite statements into their canonical form"),
+    ("fix-cidr", "repair IP address ranges stored with host bits"),
+    (
+        "reload-psl",
+        "<file>: load another copy of the Public Suffix List",
+    ),
+];
+
+/// Tab completion of commands, template names and entity types
+struct ConsoleHelper {
+    storage: Arc<RwLock<Storage>>,
+}
+
+impl ConsoleHelper {
+    /// Completions of `word`, given the part of the line before it
+    fn candidates(&self, before: &str, word: &str) -> Vec<String> {
+        let names: Vec<String> = match before {
+            "!" => COMMANDS.iter().map(|(c, _)| c.to_string()).collect(),
+            // a statement starts with the name of its template
+            "" => block_on(self.storage.read())
+                .templates()
+                .into_iter()
+                .map(|(_, t)| t.name)
+                .collect(),
+            // within parentheses, entity types are needed for new templates
+            _ if before.ends_with(['(', ',', ' ', ':']) && !before.starts_with(['!', '?']) => {
+                EntityType::ALL.iter().map(|t| t.to_string()).collect()
+            }
+            _ => vec![],
+        };
+        let mut candidates = names
+            .into_iter()
+            .filter(|n| n.starts_with(word))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+}
+
+impl Completer for ConsoleHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '-'))
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        Ok((start, self.candidates(&line[..start], &line[start..pos])))
+    }
+}
+
+impl Hinter for ConsoleHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ConsoleHelper {}
+
+impl Validator for ConsoleHelper {}
+
+impl Helper for ConsoleHelper {}
+
+/// Read lines with editing, history and completion and send them to the network loop.
+/// This blocks, so it has to run on a thread of its own. It returns on EOF or when the network loop is gone.
+pub fn run_console(
+    mut sender: Sender<String>,
+    storage: Arc<RwLock<Storage>>,
+    history_file: Option<PathBuf>,
+) {
+    let mut editor = Editor::<ConsoleHelper>::new();
+    editor.set_helper(Some(ConsoleHelper { storage }));
+    if let Some(file) = &history_file {
+        // there is no history on first start
+        let _ = editor.load_history(file);
+    }
+    loop {
+        let line = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => {
+                println!("EOF on stdin");
+                return;
+            }
+            Err(e) => return error!("could not read input: {}", e),
+        };
+        if !line.trim().is_empty() {
+            editor.add_history_entry(line.as_str());
+            if let Some(file) = &history_file {
+                if let Err(e) = editor.save_history(file) {
+                    error!("could not save history to {}: {}", file.display(), e);
+                }
+            }
+        }
+        if block_on(sender.send(line)).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn completion() {
+        let storage = block_on(Storage::temporary());
+        let helper = ConsoleHelper {
+            storage: Arc::new(RwLock::new(storage)),
+        };
+        assert_eq!(helper.candidates("!", "st"), ["stats"]);
+        assert_eq!(helper.candidates("", "tru"), ["trusts"]);
+        assert_eq!(helper.candidates("template(foo(", "IPv"), ["IPv4", "IPv6"]);
+        assert_eq!(
+            helper.candidates("template(foo(Domain,", "").len(),
+            EntityType::ALL.len()
+        );
+        assert!(helper.candidates("?", "exa").is_empty());
+        assert!(helper.candidates("!retract foo(", "Do").is_empty());
+    }
+}
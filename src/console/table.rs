@@ -0,0 +1,185 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use serde_json::{Map, Value};
+
+/// How listings are shown on the console
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OutputFormat {
+    #[default]
+    Table, // columns aligned for reading
+    Json, // one JSON array of objects per listing, for scripts
+}
+
+impl Display for OutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Table => write!(f, "table"),
+            Self::Json => write!(f, "json"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidOutputFormat;
+
+impl Display for InvalidOutputFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "output format must be table or json")
+    }
+}
+
+impl std::error::Error for InvalidOutputFormat {}
+
+impl FromStr for OutputFormat {
+    type Err = InvalidOutputFormat;
+    fn from_str(s: &str) -> Result<Self, InvalidOutputFormat> {
+        match s {
+            "table" => Ok(Self::Table),
+            "json" => Ok(Self::Json),
+            _ => Err(InvalidOutputFormat),
+        }
+    }
+}
+
+/// A listing with named columns. Cells are JSON values so that numbers and lists keep their type in JSON output
+pub struct Table {
+    columns: Vec<&'static str>,
+    rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn new(columns: &[&'static str]) -> Self {
+        Self {
+            columns: columns.to_vec(),
+            rows: vec![],
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        debug_assert_eq!(row.len(), self.columns.len());
+        self.rows.push(row);
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Table => self.render_table(),
+            OutputFormat::Json => self.render_json(),
+        }
+    }
+
+    fn render_table(&self) -> String {
+        let cells = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(cell_text).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        let mut widths = self.columns.iter().map(|c| c.len()).collect::<Vec<_>>();
+        for row in &cells {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let line = |row: &[String]| {
+            let padded = row
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", cell, width = width))
+                .collect::<Vec<_>>();
+            padded.join("  ").trim_end().to_string()
+        };
+        let header = self
+            .columns
+            .iter()
+            .map(|c| c.to_string())
+            .collect::<Vec<_>>();
+        let separator = widths.iter().map(|w| "-".repeat(*w)).collect::<Vec<_>>();
+        let mut lines = vec![line(&header), line(&separator)];
+        lines.extend(cells.iter().map(|row| line(row)));
+        lines.join("\n")
+    }
+
+    fn render_json(&self) -> String {
+        let objects = self
+            .rows
+            .iter()
+            .map(|row| {
+                let object = self
+                    .columns
+                    .iter()
+                    .map(|c| c.to_string())
+                    .zip(row.iter().cloned())
+                    .collect::<Map<_, _>>();
+                Value::Object(object)
+            })
+            .collect::<Vec<_>>();
+        Value::Array(objects).to_string()
+    }
+}
+
+// text of a cell in table output: strings without quotes, fractional numbers with three decimals,
+// lists separated by spaces, null as empty cell
+fn cell_text(value: &Value) -> String {
+    match value {
+        Value::Null => "".into(),
+        Value::String(s) => s.clone(),
+        Value::Number(n) if n.is_f64() => format!("{:.3}", n.as_f64().unwrap_or_default()),
+        Value::Array(values) => values.iter().map(cell_text).collect::<Vec<_>>().join(" "),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn example() -> Table {
+        let mut table = Table::new(&["id", "name", "addresses"]);
+        table.push(vec![json!(1), json!("first"), json!(["a", "b"])]);
+        table.push(vec![json!(12), Value::Null, json!([])]);
+        table
+    }
+
+    #[test]
+    fn table_output() {
+        assert_eq!(
+            example().render(OutputFormat::Table),
+            "id  name   addresses\n\
+             --  -----  ---------\n\
+             1   first  a b\n\
+             12"
+        );
+    }
+
+    #[test]
+    fn fractional_numbers() {
+        let mut table = Table::new(&["trust", "signer"]);
+        table.push(vec![json!(2.0 / 3.0), json!("a")]);
+        table.push(vec![json!(1.0), json!("b")]);
+        assert_eq!(
+            table.render(OutputFormat::Table),
+            "trust  signer\n\
+             -----  ------\n\
+             0.667  a\n\
+             1.000  b"
+        );
+        let parsed: Value = serde_json::from_str(&table.render(OutputFormat::Json)).unwrap();
+        assert_eq!(parsed[0]["trust"], json!(2.0 / 3.0));
+    }
+
+    #[test]
+    fn json_output() {
+        let parsed: Value = serde_json::from_str(&example().render(OutputFormat::Json)).unwrap();
+        assert_eq!(
+            parsed,
+            json!([
+                {"id": 1, "name": "first", "addresses": ["a", "b"]},
+                {"id": 12, "name": null, "addresses": []}
+            ])
+        );
+    }
+}
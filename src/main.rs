@@ -1,27 +1,143 @@
-use std::error::Error;
+use std::{error::Error, path::PathBuf, time::Duration};
 
-use async_std::{io, task::spawn};
+use async_std::task::{spawn, spawn_blocking};
 use clap::{Parser, Subcommand};
 use futures::{
-    channel::mpsc::{channel, Receiver, Sender},
-    select, AsyncBufReadExt, SinkExt, StreamExt,
+    channel::mpsc::{channel, Receiver},
+    select, StreamExt,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 
-use libp2p::{multiaddr::Protocol, swarm::SwarmEvent, Multiaddr, Swarm};
+use libp2p::{
+    multiaddr::Protocol,
+    swarm::{dial_opts::DialOpts, AddressScore, SwarmEvent},
+    Multiaddr, Swarm,
+};
 
+mod console;
 mod milter;
 mod model;
 mod reputation_net;
+mod scheduler;
 mod storage;
 
-use reputation_net::{Message, ReputationNet};
+use console::{run_console, OutputFormat};
+use model::{KeyAlgorithm, NewKey, PublicSuffixList, ScoreSettings, TrustSettings};
+use reputation_net::{
+    build_transport, read_swarm_key, resolve_listen_address, DiscoverySettings, Message,
+    MaintenanceSettings, NetworkId, NetworkSettings, ReputationNet, RpcLimits, SyncSettings,
+    TransportSettings,
+};
+use scheduler::{Job, Scheduler, SchedulerSettings};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
 struct Args {
     #[clap(short, long)]
     peer: Option<String>,
+    /// Algorithm of our own key, only used on first start (ed25519, secp256k1 or rsa)
+    #[clap(long, default_value = "secp256k1")]
+    key_type: KeyAlgorithm,
+    /// Import our own key from a file instead of generating it, only used on first start
+    #[clap(long)]
+    import_key: Option<String>,
+    /// Give all current opinions the same weight instead of decaying them towards their expiry
+    #[clap(long)]
+    no_decay: bool,
+    /// Weight of opinions from signers outside our web of trust (0.0..1.0).
+    /// Without it, they have full weight until we trust some signer and none afterwards
+    #[clap(long, parse(try_from_str = parse_fraction))]
+    default_trust: Option<f64>,
+    /// Maximum number of trusts() hops from our own key to a trusted signer
+    #[clap(long, default_value = "3")]
+    trust_depth: u8,
+    /// Factor by which trust is reduced for each hop after the first
+    #[clap(long, default_value = "0.5", parse(try_from_str = parse_fraction))]
+    trust_attenuation: f64,
+    /// Opinions of signers with less trust are ignored
+    #[clap(long, default_value = "0.1", parse(try_from_str = parse_fraction))]
+    trust_threshold: f64,
+    /// Use this copy of the Public Suffix List instead of the bundled snapshot
+    #[clap(long)]
+    public_suffix_list: Option<String>,
+    /// Check the signatures of opinions when reading them from the database
+    #[clap(long)]
+    verify_on_load: bool,
+    /// Show internationalized domain names in Unicode instead of punycode
+    #[clap(long)]
+    unicode: bool,
+    /// How listings like !peers are shown on the console (table or json)
+    #[clap(long, default_value = "table")]
+    output: OutputFormat,
+    /// File in which the console history is kept, an empty name disables it
+    #[clap(long, default_value = "reputation.history")]
+    history_file: PathBuf,
+    /// Minimum number of days up to today which are compared with connected peers,
+    /// older dates are compared as long as we have opinions on them which are still valid
+    #[clap(long, default_value = "30")]
+    sync_days: u32,
+    /// Maximum number of synchronization requests in flight
+    #[clap(long, default_value = "4")]
+    sync_concurrency: usize,
+    /// Seconds between synchronization rounds with connected peers, 0 disables them
+    #[clap(long, default_value = "600")]
+    sync_interval: u64,
+    /// Maximum size of RPC requests from peers in bytes. Reconciliation steps are sent as requests
+    /// and carry up to a page of opinions like responses
+    #[clap(long, default_value = "16777216")]
+    max_request_size: usize,
+    /// Maximum size of RPC responses from peers in bytes
+    #[clap(long, default_value = "16777216")]
+    max_response_size: usize,
+    /// Maximum number of opinions in one RPC response, larger results are sent in pages
+    #[clap(long, default_value = "1000")]
+    page_size: usize,
+    /// Multiaddress of a bootstrap peer including its /p2p/<peer id>, may be repeated
+    #[clap(long, multiple_occurrences = true)]
+    bootstrap: Vec<Multiaddr>,
+    /// Do not look for peers on the local network segment via mDNS
+    #[clap(long)]
+    no_mdns: bool,
+    /// Seconds between random walks through the DHT to discover more peers, 0 disables them
+    #[clap(long, default_value = "300")]
+    random_walk_interval: u64,
+    /// Seconds between removals of expired opinions and stale peers, 0 disables them
+    #[clap(long, default_value = "3600")]
+    cleanup_interval: u64,
+    /// Seconds between renewals of our own opinions which are about to expire, 0 disables them
+    #[clap(long, default_value = "3600")]
+    refresh_interval: u64,
+    /// Our own opinions expiring within this many days are renewed
+    #[clap(long, default_value = "7")]
+    refresh_days: u16,
+    /// Seconds between announcements of our sync infos, 0 disables them
+    #[clap(long, default_value = "300")]
+    announce_interval: u64,
+    /// Number of days up to today whose sync infos are announced
+    #[clap(long, default_value = "2")]
+    announce_days: u32,
+    /// Number of peers from earlier runs which are dialed on startup
+    #[clap(long, default_value = "8")]
+    redial_peers: usize,
+    /// Forget peers which have not been seen for this many days
+    #[clap(long, default_value = "30")]
+    peer_expiry_days: u64,
+    /// Identifier of the reputation network to join, nodes only talk to nodes of the same network
+    #[clap(long)]
+    network_id: Option<NetworkId>,
+    /// File with a pre-shared key (swarm.key format) to form a private network with nodes having the same key
+    #[clap(long)]
+    swarm_key: Option<String>,
+    /// Address to listen on, e.g. /ip6/::/tcp/10000 or /dns4/node.example.com/tcp/10080/ws, may be repeated.
+    /// Without it, the first free port in 10000..10100 on all IPv4 interfaces is used
+    #[clap(long, multiple_occurrences = true)]
+    listen: Vec<Multiaddr>,
+    /// Address under which other nodes can reach this one, e.g. behind NAT, may be repeated
+    #[clap(long, multiple_occurrences = true)]
+    external: Vec<Multiaddr>,
+    /// Enable the WebSocket transport for listening on and dialing /ws addresses
+    #[clap(long)]
+    websocket: bool,
     #[clap(subcommand)]
     command: Option<Commands>,
 }
@@ -31,17 +147,71 @@ enum Commands {
     Milter { port: Option<u16> },
 }
 
+/// Parse a trust value or factor, which must be a number in 0.0..1.0
+fn parse_fraction(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(value) if (0.0..=1.0).contains(&value) => Ok(value),
+        Ok(value) => Err(format!("{} is not in 0.0..1.0", value)),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
 #[async_std::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
     let args = Args::parse();
+    if let Some(file) = &args.public_suffix_list {
+        PublicSuffixList::load(file)?;
+    }
 
     let (input_sender, input_receiver) = channel::<String>(5);
     let (message_sender, message_receiver) = channel::<Message>(100);
+    let (job_sender, job_receiver) = channel::<Job>(1);
+
+    let new_key = match &args.import_key {
+        Some(file) => NewKey::Import(args.key_type, std::fs::read(file)?),
+        None => NewKey::Generate(args.key_type),
+    };
 
     let mut swarm = {
-        let behaviour = ReputationNet::new(message_sender).await;
-        let transport = libp2p::development_transport(behaviour.local_key.clone()).await?;
+        let settings = NetworkSettings {
+            rpc_limits: RpcLimits {
+                request_size: args.max_request_size,
+                response_size: args.max_response_size,
+                page_size: args.page_size,
+            },
+            discovery: DiscoverySettings {
+                bootstrap: args.bootstrap.clone(),
+                mdns: !args.no_mdns,
+            },
+            network_id: args.network_id.clone().unwrap_or_default(),
+        };
+        let mut behaviour = ReputationNet::new(message_sender, new_key, settings).await;
+        behaviour.unicode = args.unicode;
+        behaviour.output = args.output;
+        behaviour.set_sync_settings(SyncSettings {
+            days: args.sync_days,
+            concurrency: args.sync_concurrency,
+        });
+        behaviour.set_maintenance_settings(MaintenanceSettings {
+            peer_expiry: Duration::from_secs(args.peer_expiry_days * 86400),
+            announce_days: args.announce_days,
+        });
+        let psk = match &args.swarm_key {
+            Some(file) => Some(read_swarm_key(file)?),
+            None => None,
+        };
+        if let Some(psk) = &psk {
+            println!("Private network with key fingerprint {}", psk.fingerprint());
+        }
+        let transport = build_transport(
+            &behaviour.local_key,
+            TransportSettings {
+                psk,
+                websocket: args.websocket,
+            },
+        )
+        .await?;
         let local_peer_id = behaviour.local_peer_id();
 
         println!("Local peer id: {:?}", local_peer_id);
@@ -49,19 +219,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
         Swarm::new(transport, behaviour, local_peer_id)
     };
 
-    // Tell the swarm to listen on all interfaces and the first available port
-    // in range 10000..10100
-    for port in 10000..10100 {
-        let mut addr: Multiaddr = "/ip4/0.0.0.0".parse()?;
-        addr.push(Protocol::Tcp(port));
-        match swarm.listen_on(addr) {
-            Ok(_) => {
-                println!("Listening on port {}", port);
-                break;
+    // Tell the swarm to listen on the configured addresses, or else on all interfaces
+    // and the first available port in range 10000..10100
+    for address in &args.listen {
+        let websocket_address = address
+            .iter()
+            .any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_)));
+        if websocket_address && !args.websocket {
+            return Err(format!("listening on {} needs --websocket", address).into());
+        }
+        for address in resolve_listen_address(address).await? {
+            swarm.listen_on(address.clone())?;
+            println!("Listening on {}", address);
+        }
+    }
+    if args.listen.is_empty() {
+        for port in 10000..10100 {
+            let mut addr: Multiaddr = "/ip4/0.0.0.0".parse()?;
+            addr.push(Protocol::Tcp(port));
+            match swarm.listen_on(addr) {
+                Ok(_) => {
+                    println!("Listening on port {}", port);
+                    break;
+                }
+                _ => continue,
             }
-            _ => continue,
         }
     }
+    for address in &args.external {
+        swarm.add_external_address(address.clone(), AddressScore::Infinite);
+    }
 
     // Dial the peer identified by the multi-address given on the command line.
 
@@ -72,42 +259,84 @@ async fn main() -> Result<(), Box<dyn Error>> {
     }
 
     let storage = swarm.behaviour().storage.clone();
-    spawn(network_loop(swarm, input_receiver, message_receiver));
+    {
+        let mut storage = storage.write().await;
+        storage.set_verify_on_load(args.verify_on_load);
+        storage.set_refresh_horizon(args.refresh_days);
+        storage.set_score_settings(ScoreSettings {
+            decay: !args.no_decay,
+            default_trust: args.default_trust,
+        });
+        storage
+            .set_trust_settings(TrustSettings {
+                depth: args.trust_depth,
+                attenuation: args.trust_attenuation,
+                threshold: args.trust_threshold,
+            })
+            .await?;
+        if !storage.has_web_of_trust() {
+            warn!("no web of trust yet, opinions of all signers count until we trust some signer");
+        }
+    }
+
+    // Redial peers from earlier runs, those we recently synchronized with first
+    let known_peers = {
+        let storage = storage.read().await;
+        let expired = storage
+            .expire_peers(Duration::from_secs(args.peer_expiry_days * 86400))
+            .await?;
+        debug!("expired {} peers", expired);
+        storage.list_peers().await?
+    };
+    for peer in known_peers
+        .into_iter()
+        .filter(|peer| !peer.addresses.is_empty())
+        .take(args.redial_peers)
+    {
+        println!("Redialing {}", peer.peer_id);
+        let dial_opts = DialOpts::peer_id(peer.peer_id)
+            .addresses(peer.addresses)
+            .build();
+        if let Err(e) = swarm.dial(dial_opts) {
+            info!("could not redial {}: {:?}", peer.peer_id, e);
+        }
+    }
+
+    let scheduler = Scheduler::new(&SchedulerSettings {
+        cleanup: Duration::from_secs(args.cleanup_interval),
+        refresh: Duration::from_secs(args.refresh_interval),
+        announce: Duration::from_secs(args.announce_interval),
+        catch_up: Duration::from_secs(args.sync_interval),
+        random_walk: Duration::from_secs(args.random_walk_interval),
+    });
+    spawn(scheduler.run(job_sender));
+    spawn(network_loop(
+        swarm,
+        input_receiver,
+        message_receiver,
+        job_receiver,
+    ));
 
     if let Some(cmd) = args.command {
         match cmd {
             Commands::Milter { port } => {
                 let port = port.or(Some(21000)).unwrap();
                 println!("Running milter on port {}", port);
-                spawn(milter::run_milter(("0.0.0.0", port), storage));
+                spawn(milter::run_milter(("0.0.0.0", port), storage.clone()));
             }
         }
     }
 
-    input_reader(input_sender).await?;
+    let history_file = Some(args.history_file).filter(|file| !file.as_os_str().is_empty());
+    spawn_blocking(move || run_console(input_sender, storage, history_file)).await;
     Ok(())
 }
 
-async fn input_reader(mut sender: Sender<String>) -> Result<(), std::io::Error> {
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-    loop {
-        match stdin.next().await {
-            Some(result) => {
-                let line = result?;
-                sender.send(line).await.expect("could send");
-            }
-            None => {
-                println!("EOF on stdin");
-                return Ok(());
-            }
-        }
-    }
-}
-
 async fn network_loop(
     mut swarm: Swarm<ReputationNet>,
     mut input_receiver: Receiver<String>,
     mut message_receiver: Receiver<Message>,
+    mut job_receiver: Receiver<Job>,
 ) -> Result<(), std::io::Error> {
     loop {
         select! {
@@ -117,8 +346,8 @@ async fn network_loop(
                     Some(SwarmEvent::Behaviour(s)) => {
                         swarm.behaviour_mut().handle_behaviour_event(s);
                     }
-                    Some(SwarmEvent::ConnectionEstablished{peer_id, endpoint: _, num_established, concurrent_dial_errors: _}) => {
-                        swarm.behaviour_mut().handle_connection_established(peer_id, u32::from(num_established));
+                    Some(SwarmEvent::ConnectionEstablished{peer_id, endpoint, num_established, concurrent_dial_errors: _}) => {
+                        swarm.behaviour_mut().handle_connection_established(peer_id, &endpoint, u32::from(num_established));
                     }
                     Some(SwarmEvent::ConnectionClosed{peer_id, endpoint: _, num_established, cause: _}) => {
                         swarm.behaviour_mut().handle_connection_closed(peer_id, num_established);
@@ -144,6 +373,26 @@ async fn network_loop(
                     None => panic!("end of network?")
                 }
             }
+            job = job_receiver.next() => {
+                if let Some(job) = job {
+                    swarm.behaviour_mut().run_job(job).await;
+                }
+            }
+        }
+        swarm.behaviour_mut().drive_catch_up().await;
+        for (peer_id, addresses) in swarm.behaviour_mut().take_dial_candidates() {
+            // peers we are connected to already are not dialed again
+            let dial_opts = DialOpts::peer_id(peer_id).addresses(addresses).build();
+            if let Err(e) = swarm.dial(dial_opts) {
+                debug!("could not dial {}: {:?}", peer_id, e);
+            }
+        }
+        let (ban, unban) = swarm.behaviour_mut().take_ban_changes();
+        for peer_id in ban {
+            swarm.ban_peer_id(peer_id);
+        }
+        for peer_id in unban {
+            swarm.unban_peer_id(peer_id);
         }
     }
 }
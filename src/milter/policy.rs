@@ -9,7 +9,7 @@ use regex::Regex;
 use unicase::UniCase;
 
 use crate::{
-    model::{Entity, Statement},
+    model::{Entity, Score, Statement},
     storage::Storage,
 };
 
@@ -38,10 +38,14 @@ pub enum Severity {
     Known = 4
 }
 
+// statements with a lower score only lead to a temporary failure instead of a rejection
+const REJECT_SCORE: f64 = 1.0;
+
 struct Match {
     location: Location,
     entity: Entity,
     statement: Statement,
+    score: Score,
 }
 
 pub struct PolicyAccumulator {
@@ -51,6 +55,15 @@ pub struct PolicyAccumulator {
     severity: Severity,
 }
 
+impl Match {
+    fn severity(&self) -> Severity {
+        match self.statement.severity() {
+            Severity::Reject if self.score.value < REJECT_SCORE => Severity::Tempfail,
+            severity => severity,
+        }
+    }
+}
+
 impl Statement {
     fn severity(&self) -> Severity {
         match self.name.as_str() {
@@ -88,7 +101,7 @@ impl PolicyAccumulator {
         match self
             .statements
             .iter()
-            .find(|m| m.statement.severity() == self.severity)
+            .find(|m| m.severity() == self.severity)
         {
             Some(m) => {
                 if m.entity == m.statement.entities[0] {
@@ -112,23 +125,30 @@ impl PolicyAccumulator {
             if statements.len() == 0 {
                 // println!("milter no match for {} in {}", entity, location.reason());
             }
-            for statement in statements {
+            for (statement, score) in statements {
                 println!(
-                    "{}: {} in {} ({})",
+                    "{}: {} in {} ({}, score {})",
                     match &self.macros.get("i") {
                         Some(s) => s,
                         None => "NOQUEUE",
                     },
                     entity,
                     location.reason(),
-                    statement
+                    statement,
+                    score
                 );
-                self.severity = self.severity.max(statement.severity());
-                self.statements.push(Match {
+                if !score.is_positive() {
+                    // opinions against the statement outweigh those for it
+                    continue;
+                }
+                let m = Match {
                     location,
                     entity: entity.clone(),
                     statement,
-                });
+                    score,
+                };
+                self.severity = self.severity.max(m.severity());
+                self.statements.push(m);
             }
         }
     }
@@ -212,14 +232,14 @@ impl PolicyAccumulator {
         }
     }
 
-    async fn statements_about(&self, entity: &Entity) -> Vec<Statement> {
+    async fn statements_about(&self, entity: &Entity) -> Vec<(Statement, Score)> {
         let storage = self.storage.read().await;
         storage
-            .find_statements_about(entity)
+            .score_statements_about(entity)
             .await
             .unwrap()
             .into_iter()
-            .map(|ps| ps.data)
+            .map(|(ps, score)| (ps.data, score))
             .collect()
     }
 }
@@ -0,0 +1,185 @@
+// Canonical binary encoding of statements and opinions, used as input for signatures.
+// Unlike the textual representation, this encoding does not change when entity formatting changes.
+// All numbers are big endian, strings and nested values are prefixed with their length as u32.
+
+use super::{
+    template::{Constraint, Parameter, Range},
+    Entity, PublicKey, Statement, Template, UnsignedOpinion,
+};
+
+/// Domain separation prefix for version 2 opinion signatures
+pub const OPINION_V2_PREFIX: &[u8] = b"reputation-net/opinion/v2\0";
+
+#[derive(Default)]
+pub struct Encoder {
+    bytes: Vec<u8>,
+}
+
+pub trait Canonical {
+    fn encode(&self, encoder: &mut Encoder);
+
+    fn canonical_bytes(&self) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        self.encode(&mut encoder);
+        encoder.bytes
+    }
+}
+
+impl Encoder {
+    pub fn raw(&mut self, bytes: &[u8]) -> &mut Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn u8(&mut self, value: u8) -> &mut Self {
+        self.bytes.push(value);
+        self
+    }
+
+    pub fn u16(&mut self, value: u16) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    pub fn u32(&mut self, value: u32) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    pub fn i8(&mut self, value: i8) -> &mut Self {
+        self.raw(&value.to_be_bytes())
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.u32(bytes.len() as u32).raw(bytes)
+    }
+
+    pub fn str(&mut self, s: &str) -> &mut Self {
+        self.bytes(s.as_bytes())
+    }
+
+    pub fn nested(&mut self, value: &impl Canonical) -> &mut Self {
+        self.bytes(&value.canonical_bytes())
+    }
+
+    fn optional_u32(&mut self, value: Option<u32>) -> &mut Self {
+        match value {
+            Some(n) => self.u8(1).u32(n),
+            None => self.u8(0),
+        }
+    }
+}
+
+impl Canonical for Entity {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.u8(self.entity_type() as u8);
+        match self {
+            // hash values are encoded as written, decoding them would make `#YWJj` and `#abc` the same
+            Entity::Domain(s) | Entity::EMail(s) | Entity::Url(s) | Entity::HashValue(s) => {
+                encoder.str(s)
+            }
+            // the entity type determines the size of numbers and addresses
+            Entity::AS(n) => encoder.u32(*n),
+            Entity::IPv4(cidr) => encoder
+                .raw(&cidr.first_address().octets())
+                .u8(cidr.network_length()),
+            Entity::IPv6(cidr) => encoder
+                .raw(&cidr.first_address().octets())
+                .u8(cidr.network_length()),
+            Entity::Signer(pk) => encoder.nested(pk),
+            Entity::Template(template) => encoder.nested(template),
+        };
+    }
+}
+
+impl Canonical for PublicKey {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.raw(&self.key.to_protobuf_encoding());
+    }
+}
+
+impl Canonical for Template {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.str(&self.name).u32(self.parameters.len() as u32);
+        for parameter in &self.parameters {
+            encoder.nested(parameter);
+        }
+    }
+}
+
+impl Canonical for Parameter {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder
+            .str(self.name.as_deref().unwrap_or(""))
+            .u32(self.entity_types.len() as u32);
+        for entity_type in &self.entity_types {
+            encoder.u8(*entity_type as u8);
+        }
+        encoder.u32(self.constraints.len() as u32);
+        for constraint in &self.constraints {
+            match constraint {
+                Constraint::Prefix(range) => encoder.u8(1).nested(range),
+                Constraint::Depth(range) => encoder.u8(2).nested(range),
+                Constraint::Value(range) => encoder.u8(3).nested(range),
+                Constraint::Suffix => encoder.u8(4),
+            };
+        }
+    }
+}
+
+impl Canonical for Range {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.optional_u32(self.min).optional_u32(self.max);
+    }
+}
+
+impl Canonical for Statement {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.str(&self.name).u32(self.entities.len() as u32);
+        for entity in &self.entities {
+            encoder.nested(entity);
+        }
+    }
+}
+
+impl UnsignedOpinion {
+    /// The bytes to be signed by `signer` for a version 2 signature of this opinion about `statement`
+    pub fn canonical_signable_bytes(&self, statement: &Statement, signer: &PublicKey) -> Vec<u8> {
+        let mut encoder = Encoder::default();
+        encoder
+            .raw(OPINION_V2_PREFIX)
+            .nested(statement)
+            .nested(signer)
+            .u32(self.date.d)
+            .u16(self.valid)
+            .u8(self.serial)
+            .i8(self.certainty)
+            .str(&self.comment);
+        encoder.bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn statement() {
+        let statement = Statement::from_str("asn(192.0.2.0/24,AS5)").unwrap();
+        assert_eq!(
+            statement.canonical_bytes(),
+            vec![
+                0, 0, 0, 3, b'a', b's', b'n', // name
+                0, 0, 0, 2, // number of entities
+                0, 0, 0, 6, 7, 192, 0, 2, 0, 24, // IPv4 entity
+                0, 0, 0, 5, 6, 0, 0, 0, 5, // AS entity
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_values() {
+        let encoded = Statement::from_str("spam(#YWJj)").unwrap().canonical_bytes();
+        let raw = Statement::from_str("spam(#abc)").unwrap().canonical_bytes();
+        assert_ne!(encoded, raw);
+    }
+}
@@ -5,7 +5,12 @@ use cidr::{Ipv4Cidr, Ipv6Cidr};
 use libp2p::multihash::{Hasher, Sha2_256};
 use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
 
-use super::{template::Template, PublicKey};
+use super::{
+    parser::unicode_domain,
+    public_suffix::{is_public_suffix, registrable_domain},
+    template::Template,
+    PublicKey,
+};
 
 // EntityType is used within Templates
 #[derive(Copy, Clone, PartialEq, Debug, Eq, Hash)]
@@ -83,6 +88,7 @@ impl FromStr for EntityType {
             "IPv4" => Self::IPv4,
             "IPv6" => Self::IPv6,
             "Signer" => Self::Signer,
+            "Url" => Self::Url,
             "HashValue" => Self::HashValue,
             "Template" => Self::Template,
             _ => return Err(InvalidEntityType),
@@ -91,6 +97,20 @@ impl FromStr for EntityType {
     }
 }
 
+impl EntityType {
+    pub const ALL: [EntityType; 9] = [
+        Self::Template,
+        Self::Signer,
+        Self::Domain,
+        Self::EMail,
+        Self::HashValue,
+        Self::AS,
+        Self::IPv4,
+        Self::IPv6,
+        Self::Url,
+    ];
+}
+
 impl Entity {
     pub fn hash_string(string: &str) -> Self {
         let digest = Sha2_256::digest(string.as_bytes());
@@ -120,6 +140,10 @@ impl Entity {
 
     pub fn domain(&self) -> Option<Self> {
         match self {
+            Self::Url(_) => match self.host() {
+                Some(Self::Domain(host)) => Some(Self::Domain(host)),
+                _ => None,
+            },
             Self::EMail(address) => {
                 let at_index = address.find("@").unwrap();
                 Some(Self::Domain(address[at_index + 1..].into()))
@@ -145,7 +169,43 @@ impl Entity {
         }
     }
 
-    /// Return a list of all lookup keys that should be considered to find matching statements, from most to least specific
+    /// Return the host part of an URL, either a domain or an IP address
+    pub fn host(&self) -> Option<Self> {
+        match self {
+            Self::Url(url) => {
+                let rest = &url[url.find("://")? + 3..];
+                let end = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+                let authority = &rest[..end];
+                let host = match authority.rfind(':') {
+                    Some(n) if !authority.ends_with(']') => &authority[..n],
+                    _ => authority,
+                };
+                Self::from_str(host.trim_start_matches('[').trim_end_matches(']')).ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the registrable domain (a public suffix plus one label) of a domain, e-mail address or URL
+    pub fn registrable_domain(&self) -> Option<Self> {
+        match self {
+            Self::Domain(domain) => registrable_domain(domain).map(Self::Domain),
+            Self::EMail(_) | Self::Url(_) => self.domain()?.registrable_domain(),
+            _ => None,
+        }
+    }
+
+    /// Check whether this is a public suffix like `co.uk` or `com.` under which domains can be registered
+    pub fn is_public_suffix(&self) -> bool {
+        match self {
+            Self::Domain(domain) => is_public_suffix(domain),
+            _ => false,
+        }
+    }
+
+    /// Return a list of all lookup keys that should be considered to find matching statements, from most to least specific.
+    /// Domains are looked up with their parent domains up to the registrable domain. Public suffixes like `co.uk`
+    /// are not looked up for their subdomains, statements about them (see the `[suffix]` constraint) are only found directly.
     pub fn all_lookup_keys(&self) -> Vec<Self> {
         match self {
             Self::EMail(_) => {
@@ -156,8 +216,24 @@ impl Entity {
             }
             Self::Domain(_) => {
                 let mut result = vec![self.clone()];
-                if let Some(super_domain) = self.domain() {
-                    result.append(&mut super_domain.all_lookup_keys())
+                if let Some(registrable) = self.registrable_domain() {
+                    let mut domain = self.clone();
+                    while domain != registrable {
+                        match domain.domain() {
+                            Some(super_domain) => {
+                                result.push(super_domain.clone());
+                                domain = super_domain;
+                            }
+                            None => break,
+                        }
+                    }
+                }
+                result
+            }
+            Self::Url(_) => {
+                let mut result = vec![self.clone()];
+                if let Some(host) = self.host() {
+                    result.append(&mut host.all_lookup_keys())
                 }
                 result
             }
@@ -165,6 +241,34 @@ impl Entity {
         }
     }
 
+    /// Representation for display, with internationalized domain names shown in Unicode
+    pub fn to_unicode(&self) -> String {
+        match self {
+            Self::Domain(domain) => unicode_domain(domain),
+            Self::EMail(address) => match address.rsplit_once('@') {
+                Some((localpart, domain)) => format!("{}@{}", localpart, unicode_domain(domain)),
+                None => address.clone(),
+            },
+            Self::Url(url) => {
+                let start = url.find("://").map_or(0, |n| n + 3);
+                let end = url[start..]
+                    .find([':', '/', '?', '#'])
+                    .map_or(url.len(), |n| start + n);
+                if url[start..].starts_with('[') {
+                    url.clone()
+                } else {
+                    format!(
+                        "{}{}{}",
+                        &url[..start],
+                        unicode_domain(&url[start..end]),
+                        &url[end..]
+                    )
+                }
+            }
+            _ => self.to_string(),
+        }
+    }
+
     /// Return a pair of cidr_min and cidr_max strings for database indexing
     pub fn cidr_minmax(&self) -> (Option<String>, Option<String>) {
         match self {
@@ -264,6 +368,38 @@ mod tests {
         assert_eq!(entity.to_string(), INPUT);
     }
     #[test]
+    fn all_entity_types() {
+        // there is no wildcard, so a new entity type does not compile until it is added here and to ALL
+        let position = |t: EntityType| match t {
+            EntityType::Template => 0,
+            EntityType::Signer => 1,
+            EntityType::Domain => 2,
+            EntityType::EMail => 3,
+            EntityType::HashValue => 4,
+            EntityType::AS => 5,
+            EntityType::IPv4 => 6,
+            EntityType::IPv6 => 7,
+            EntityType::Url => 8,
+        };
+        for (i, t) in EntityType::ALL.iter().enumerate() {
+            assert_eq!(position(*t), i);
+            assert_eq!(*t as usize, i + 1);
+            assert_eq!(EntityType::from_str(&t.to_string()).unwrap(), *t);
+        }
+    }
+    #[test]
+    fn unicode() {
+        for input in [
+            "bücher.example",
+            "user@bücher.example",
+            "https://bücher.example:8443/abuse",
+        ] {
+            let entity = Entity::from_str(input).unwrap();
+            assert!(entity.to_string().contains("xn--bcher-kva.example"));
+            assert_eq!(entity.to_unicode(), input);
+        }
+    }
+    #[test]
     fn email() {
         const INPUT: &str = "user@example.com";
         let entity: Entity = INPUT.parse().unwrap();
@@ -296,6 +432,7 @@ mod tests {
             "IPv4",
             "IPv6",
             "Signer",
+            "Url",
             "HashValue",
             "Template",
         ] {
@@ -317,12 +454,30 @@ mod tests {
         let domain = Entity::Domain("domain.example.biz".into());
         assert_eq!(
             domain.all_lookup_keys(),
-            vec![
-                domain.clone(),
-                Entity::Domain("example.biz".into()),
-                Entity::Domain("biz.".into())
-            ]
-        )
+            vec![domain.clone(), Entity::Domain("example.biz".into())]
+        );
+        let domain = Entity::Domain("foo.example.co.uk".into());
+        let keys = domain.all_lookup_keys();
+        assert_eq!(
+            keys,
+            vec![domain.clone(), Entity::Domain("example.co.uk".into())]
+        );
+        assert!(!keys.contains(&Entity::Domain("co.uk".into())));
+        assert!(!keys.contains(&Entity::Domain("uk.".into())));
+        let suffix = Entity::Domain("co.uk".into());
+        assert_eq!(suffix.all_lookup_keys(), vec![suffix.clone()]);
+    }
+    #[test]
+    fn registrable_domain() {
+        let domain = Entity::Domain("example.co.uk".into());
+        for input in ["mail.example.co.uk", "user@example.co.uk", "https://www.example.co.uk/"] {
+            let entity = Entity::from_str(input).unwrap();
+            assert_eq!(entity.registrable_domain().as_ref(), Some(&domain));
+        }
+        assert!(Entity::Domain("co.uk".into()).is_public_suffix());
+        assert!(Entity::Domain("uk.".into()).is_public_suffix());
+        assert!(!domain.is_public_suffix());
+        assert_eq!(Entity::Domain("co.uk".into()).registrable_domain(), None);
     }
     #[test]
     fn email_lookup_keys() {
@@ -332,8 +487,38 @@ mod tests {
             vec![
                 email.clone(),
                 email.hash_emails(),
-                Entity::Domain("example.com".into()),
-                Entity::Domain("com.".into())
+                Entity::Domain("example.com".into())
+            ]
+        )
+    }
+    #[test]
+    fn url() {
+        let entity: Entity = "HTTPS://Abuse.Example.COM:443/Contact?Form=1".parse().unwrap();
+        assert_eq!(
+            entity,
+            Entity::Url("https://abuse.example.com/Contact?Form=1".into())
+        );
+        assert_eq!(entity.entity_type(), EntityType::Url);
+        let entity: Entity = "http://example.com:8080".parse().unwrap();
+        assert_eq!(entity.to_string(), "http://example.com:8080/");
+    }
+    #[test]
+    fn url_lookup_keys() {
+        let url: Entity = "https://www.evil.example/form".parse().unwrap();
+        assert_eq!(
+            url.all_lookup_keys(),
+            vec![
+                url.clone(),
+                Entity::Domain("www.evil.example".into()),
+                Entity::Domain("evil.example".into())
+            ]
+        );
+        let url: Entity = "http://192.0.2.1/".parse().unwrap();
+        assert_eq!(
+            url.all_lookup_keys(),
+            vec![
+                url.clone(),
+                Entity::IPv4(Ipv4Cidr::from_str("192.0.2.1").unwrap())
             ]
         )
     }
@@ -2,20 +2,27 @@ use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTRO
 
 use libp2p::identity::Keypair;
 
+mod canonical;
 mod entity;
 mod opinion;
 pub mod parser;
+mod public_suffix;
 mod publickey;
+mod score;
 mod statement;
 mod template;
+mod trust;
 mod own_key;
 mod date;
 pub use entity::{Entity, EntityType};
-pub use opinion::{UnsignedOpinion,Opinion,SignedStatement};
+pub use opinion::{UnsignedOpinion,Opinion,OpinionParameter,SignatureFormat,SignedStatement};
+pub use public_suffix::PublicSuffixList;
 pub use publickey::{PublicKey, Signature};
+pub use score::{Score, ScoreSettings};
 pub use statement::Statement;
 pub use template::Template;
-pub use own_key::OwnKey;
+pub use trust::{compute_trust, TrustEdge, TrustSettings};
+pub use own_key::{KeyAlgorithm, NewKey, OwnKey};
 pub use date::Date;
 
 fn percent_encode(s: &str) -> String {
@@ -4,9 +4,9 @@ use std::num::ParseIntError;
 use std::ops::Deref;
 use std::str::FromStr;
 
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 
-use super::{percent_decode, percent_encode, Keypair, PublicKey, Signature, Statement, Date};
+use super::{percent_decode, percent_encode, Date, Keypair, PublicKey, Signature, Statement};
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct UnsignedOpinion {
@@ -17,41 +17,109 @@ pub struct UnsignedOpinion {
     pub comment: String, // optional comment, may be empty
 }
 
+/// The format of the bytes covered by an opinion signature
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignatureFormat {
+    V1 = 1, // textual opinion followed by textual statement
+    V2 = 2, // canonical binary encoding, see canonical.rs
+}
+
 #[derive(Clone, Debug)]
 pub struct Opinion {
     pub data: UnsignedOpinion,
     pub signer: PublicKey,
     pub signature: Signature,
+    pub format: SignatureFormat,
+}
+
+/// An opinion parameter given by the user when signing a statement
+#[derive(Clone, Debug, PartialEq)]
+pub enum OpinionParameter {
+    Certainty(i8),
+    Valid(u16), // days
+    Comment(String),
 }
 
 // SignedStatement is actually a list of signed opinions about a single statement
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "ReceivedStatement")]
 pub struct SignedStatement {
     pub statement: Statement,
     pub opinions: Vec<Opinion>,
+    #[serde(skip_serializing)]
+    pub received: Option<String>, // the statement as written by a peer, V1 signatures cover this text
+}
+
+// a signed statement as received from a peer, before the statement is parsed and normalized
+#[derive(Deserialize)]
+struct ReceivedStatement {
+    statement: String,
+    opinions: Vec<Opinion>,
+}
+
+impl TryFrom<ReceivedStatement> for SignedStatement {
+    type Error = InvalidFormat;
+    fn try_from(received: ReceivedStatement) -> Result<Self, Self::Error> {
+        let statement = Statement::from_str(&received.statement).map_err(|_| InvalidFormat {
+            cause: "invalid statement".into(),
+        })?;
+        Ok(Self {
+            statement,
+            opinions: received.opinions,
+            received: Some(received.statement),
+        })
+    }
 }
 
 impl UnsignedOpinion {
-    pub fn sign_using(self, statement_bytes: &Vec<u8>, keypair: &Keypair) -> Opinion {
-        // return a signed version. If original is already signed, strip signature first
+    pub fn sign_using(self, statement: &Statement, keypair: &Keypair) -> Opinion {
+        // return a signed version. New signatures always use the current format
+        self.sign_using_format(statement, keypair, SignatureFormat::V2)
+    }
 
-        let signature = keypair.sign(&self.signable_bytes(statement_bytes)).unwrap();
+    /// Sign in a given format, older formats are only used for peers which do not know the current one
+    pub fn sign_using_format(
+        self,
+        statement: &Statement,
+        keypair: &Keypair,
+        format: SignatureFormat,
+    ) -> Opinion {
+        let signer = PublicKey {
+            key: keypair.public(),
+        };
+        let signable_bytes = match format {
+            SignatureFormat::V1 => self.signable_bytes(statement),
+            SignatureFormat::V2 => self.canonical_signable_bytes(statement, &signer),
+        };
+        let signature = keypair.sign(&signable_bytes).unwrap();
         Opinion {
             data: self,
-            signer: PublicKey {
-                key: keypair.public(),
-            },
+            signer,
             signature: signature,
+            format,
+        }
+    }
+
+    /// Replace the values given by the user
+    pub fn with_parameters(mut self, parameters: &[OpinionParameter]) -> Self {
+        for parameter in parameters {
+            match parameter {
+                OpinionParameter::Certainty(certainty) => self.certainty = *certainty,
+                OpinionParameter::Valid(valid) => self.valid = *valid,
+                OpinionParameter::Comment(comment) => self.comment = comment.clone(),
+            }
         }
+        self
     }
 
     pub fn last_date(&self) -> Date {
         self.date + self.valid
     }
 
-    fn signable_bytes(&self, statement_bytes: &Vec<u8>) -> Vec<u8> {
+    // signable bytes of the legacy (V1) format
+    fn signable_bytes(&self, statement: &Statement) -> Vec<u8> {
         let mut bytes = self.to_string().as_bytes().to_vec();
-        bytes.extend(statement_bytes);
+        bytes.extend(statement.signable_bytes());
         bytes
     }
 }
@@ -102,6 +170,16 @@ impl From<ParseIntError> for InvalidFormat {
     }
 }
 
+impl SignatureFormat {
+    pub fn from_i64(n: i64) -> Option<Self> {
+        match n {
+            1 => Some(Self::V1),
+            2 => Some(Self::V2),
+            _ => None,
+        }
+    }
+}
+
 impl FromStr for UnsignedOpinion {
     type Err = InvalidFormat;
     fn from_str(s: &str) -> Result<Self, InvalidFormat> {
@@ -124,15 +202,29 @@ impl FromStr for UnsignedOpinion {
 }
 
 impl Opinion {
-    #[allow(dead_code)]
-    pub fn verify_signature(&self, statement_bytes: &Vec<u8>) -> bool {
-        let signable_bytes = self.data.signable_bytes(statement_bytes);
+    pub fn verify_signature(&self, statement: &Statement) -> bool {
+        let signable_bytes = match self.format {
+            SignatureFormat::V1 => self.data.signable_bytes(statement),
+            SignatureFormat::V2 => self.data.canonical_signable_bytes(statement, &self.signer),
+        };
+        self.signer.key.verify(&signable_bytes, &self.signature)
+    }
+
+    // verify a V1 signature over the statement as written by the signer, which may not be normalized
+    fn verify_v1_text(&self, statement: &str) -> bool {
+        let mut signable_bytes = self.data.to_string().into_bytes();
+        signable_bytes.extend(statement.as_bytes());
         self.signer.key.verify(&signable_bytes, &self.signature)
     }
 }
 
+// V1 opinions are written as date;valid;serial;certainty;comment;signer;signature,
+// later formats are prefixed with their version, e.g. v2;date;...
 impl Display for Opinion {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.format != SignatureFormat::V1 {
+            write!(f, "v{};", self.format as u8)?;
+        }
         write!(
             f,
             "{};{};{}",
@@ -146,6 +238,15 @@ impl Display for Opinion {
 impl FromStr for Opinion {
     type Err = InvalidFormat;
     fn from_str(s: &str) -> Result<Self, InvalidFormat> {
+        let (format, s) = match s.split_once(";") {
+            Some(("v2", rest)) => (SignatureFormat::V2, rest),
+            Some((version, _)) if version.starts_with('v') => {
+                return Err(InvalidFormat {
+                    cause: format!("unsupported signature format {}", version),
+                })
+            }
+            _ => (SignatureFormat::V1, s),
+        };
         let parts: Vec<&str> = s.split(";").collect();
         if parts.len() != 7 {
             return Err(InvalidFormat {
@@ -165,19 +266,81 @@ impl FromStr for Opinion {
         };
         let result = Self {
             data: opinion,
-            signer: parts[5].parse().unwrap(),
-            signature: base64::decode(parts[6]).unwrap(),
+            signer: parts[5].parse().map_err(|_| InvalidFormat {
+                cause: "invalid signer".into(),
+            })?,
+            signature: base64::decode(parts[6]).map_err(|e| InvalidFormat {
+                cause: e.to_string(),
+            })?,
+            format,
         };
         Ok(result)
     }
 }
 
+// Opinions are serialized as strings in human readable formats like JSON,
+// and as a tuple with binary signer and signature in compact formats like CBOR
+#[derive(Serialize, Deserialize)]
+struct CompactOpinion(u8, u32, u16, u8, i8, String, Bytes, Bytes);
+
+// a byte string, serialized with serialize_bytes instead of as a sequence
+struct Bytes(Vec<u8>);
+
+struct BytesVisitor;
+
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte string")
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(Bytes(v.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(Bytes(v))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_byte_buf(BytesVisitor)
+    }
+}
+
 impl Serialize for Opinion {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        if serializer.is_human_readable() {
+            return serializer.serialize_str(&self.to_string());
+        }
+        CompactOpinion(
+            self.format as u8,
+            self.date.d,
+            self.valid,
+            self.serial,
+            self.certainty,
+            self.comment.clone(),
+            Bytes(self.signer.key.to_protobuf_encoding()),
+            Bytes(self.signature.clone()),
+        )
+        .serialize(serializer)
     }
 }
 
@@ -187,11 +350,31 @@ impl<'de> Deserialize<'de> for Opinion {
         D: Deserializer<'de>,
     {
         use serde::de::Error;
-        let s: &str = Deserialize::deserialize(deserializer)?;
-        match Opinion::from_str(s) {
-            Ok(e) => Ok(e),
-            Err(_) => Err(D::Error::custom("a SignedOpinion")),
+        if deserializer.is_human_readable() {
+            let s: &str = Deserialize::deserialize(deserializer)?;
+            return match Opinion::from_str(s) {
+                Ok(e) => Ok(e),
+                Err(_) => Err(D::Error::custom("a SignedOpinion")),
+            };
         }
+        let CompactOpinion(format, date, valid, serial, certainty, comment, signer, signature) =
+            Deserialize::deserialize(deserializer)?;
+        Ok(Opinion {
+            data: UnsignedOpinion {
+                date: Date::from(date),
+                valid,
+                serial,
+                certainty,
+                comment,
+            },
+            signer: PublicKey {
+                key: libp2p::identity::PublicKey::from_protobuf_encoding(&signer.0)
+                    .map_err(|_| D::Error::custom("a public key"))?,
+            },
+            signature: signature.0,
+            format: SignatureFormat::from_i64(format.into())
+                .ok_or_else(|| D::Error::custom("a known signature format"))?,
+        })
     }
 }
 
@@ -203,14 +386,37 @@ impl Deref for Opinion {
 }
 
 impl SignedStatement {
-    #[allow(dead_code)]
+    pub fn new(statement: Statement, opinions: Vec<Opinion>) -> Self {
+        Self {
+            statement,
+            opinions,
+            received: None,
+        }
+    }
+
+    /// true if the statement was received in another wording than its normalized one,
+    /// e.g. `spammer(Example.COM)` from a node which does not normalize domain names
+    pub fn is_reworded(&self) -> bool {
+        match &self.received {
+            Some(text) => *text != self.statement.to_string(),
+            None => false,
+        }
+    }
+
+    /// Check the signature of one of the opinions. V1 signatures of reworded statements
+    /// are checked against the statement as it was received
+    pub fn verify_opinion(&self, opinion: &Opinion) -> bool {
+        match &self.received {
+            Some(text) if opinion.format == SignatureFormat::V1 && self.is_reworded() => {
+                opinion.verify_v1_text(text)
+            }
+            _ => opinion.verify_signature(&self.statement),
+        }
+    }
+
+    #[cfg(test)]
     pub fn verify_signatures(&self) -> bool {
-        let statement_bytes = self.statement.signable_bytes();
-        self.opinions.len() > 0
-            && self
-                .opinions
-                .iter()
-                .all(|x| x.verify_signature(&statement_bytes))
+        !self.opinions.is_empty() && self.opinions.iter().all(|x| self.verify_opinion(x))
     }
 }
 
@@ -241,10 +447,7 @@ impl FromStr for SignedStatement {
             .map(|s| Ok(s.parse::<Opinion>()?))
             .collect::<Result<Vec<Opinion>, InvalidFormat>>()?;
 
-        Ok(Self {
-            statement: statement,
-            opinions: opinions,
-        })
+        Ok(Self::new(statement, opinions))
     }
 }
 
@@ -276,18 +479,96 @@ mod tests {
         assert_eq!(opinion, parsed)
     }
 
+    #[test]
+    fn parse_invalid() {
+        let signer = super::super::tests::example_signer();
+        for s in [
+            "18924;7;0;3;".to_string(),
+            format!("18924;7;0;3;;{}", signer),
+            format!("18924;7;0;x;;{};AA==", signer),
+            format!("18924;7;0;3;;{};not base64", signer),
+            "18924;7;0;3;;nokey;AA==".to_string(),
+            format!("v9;18924;7;0;3;;{};AA==", signer),
+        ] {
+            assert!(Opinion::from_str(&s).is_err(), "{}", s);
+            // peers sending these get an error instead of crashing us
+            let json = serde_json::to_string(&s).unwrap();
+            assert!(serde_json::from_str::<Opinion>(&json).is_err(), "{}", s);
+        }
+    }
+
     #[test]
     fn sign() {
         let opinion = example();
         let statement = super::super::statement::tests::example();
         let keypair = super::super::tests::example_keypair();
-        let signed_opinion = opinion.sign_using(&statement.signable_bytes(), &keypair);
+        let signed_opinion = opinion.sign_using(&statement, &keypair);
         let signer = super::super::tests::example_signer();
-        let signature = "MEQCIGPqfQzTjTFWTHNPT+KIMqGDvN1VV5HF0S6JWgb8n+WnAiBFGcls4ZILhxP0GWvcLdkhbUwSkZ+TaO/lf+4Hs/bf2w==";
+        let signature = "MEUCIQDSd1ebOwcj9v3ByvGpPo6208L/cdv3i4sWaVNqUqyRmwIgRmQnzCZr9sfD+6ofODSQiI+0l87elSmSb0Lh9FC9PNQ=";
         assert_eq!(
             signed_opinion.to_string(),
-            format!("18924;7;0;3;;{};{}", signer, signature)
-        )
+            format!("v2;18924;7;0;3;;{};{}", signer, signature)
+        );
+        assert!(signed_opinion.verify_signature(&statement));
+    }
+
+    #[test]
+    fn verify_v1() {
+        let statement = super::super::statement::tests::example();
+        let signer = super::super::tests::example_signer();
+        let signature = "MEQCIGPqfQzTjTFWTHNPT+KIMqGDvN1VV5HF0S6JWgb8n+WnAiBFGcls4ZILhxP0GWvcLdkhbUwSkZ+TaO/lf+4Hs/bf2w==";
+        let opinion_string = format!("18924;7;0;3;;{};{}", signer, signature);
+        let mut opinion = Opinion::from_str(&opinion_string).unwrap();
+        assert_eq!(opinion.format, SignatureFormat::V1);
+        assert_eq!(opinion.to_string(), opinion_string);
+        assert!(opinion.verify_signature(&statement));
+        // the same signature is not valid in the canonical format
+        opinion.format = SignatureFormat::V2;
+        assert!(!opinion.verify_signature(&statement));
+        let keypair = super::super::tests::example_keypair();
+        let signed_v1 = example().sign_using_format(&statement, &keypair, SignatureFormat::V1);
+        assert_eq!(signed_v1.format, SignatureFormat::V1);
+        assert!(signed_v1.verify_signature(&statement));
+    }
+
+    #[test]
+    fn verify_v1_reworded() {
+        // a version 1 node signed the statement as typed, without normalizing the domain name
+        let keypair = super::super::tests::example_keypair();
+        let mut signable_bytes = example().to_string().into_bytes();
+        signable_bytes.extend(b"spammer(Example.COM)");
+        let opinion = Opinion {
+            data: example(),
+            signer: PublicKey {
+                key: keypair.public(),
+            },
+            signature: keypair.sign(&signable_bytes).unwrap(),
+            format: SignatureFormat::V1,
+        };
+        let json = format!(
+            r#"{{"statement":"spammer(Example.COM)","opinions":["{}"]}}"#,
+            opinion
+        );
+        let signed_statement: SignedStatement = serde_json::from_str(&json).unwrap();
+        assert_eq!(signed_statement.statement.to_string(), "spammer(example.com)");
+        assert!(signed_statement.is_reworded());
+        assert!(!signed_statement.opinions[0].verify_signature(&signed_statement.statement));
+        assert!(signed_statement.verify_signatures());
+    }
+
+    #[test]
+    fn compact_encoding() {
+        let statement = super::super::statement::tests::example();
+        let keypair = super::super::tests::example_keypair();
+        let opinions = vec![example().sign_using(&statement, &keypair)];
+        let signed_statement = SignedStatement::new(statement, opinions);
+        let json = serde_json::to_vec(&signed_statement).unwrap();
+        let cbor = serde_cbor::to_vec(&signed_statement).unwrap();
+        assert!(cbor.len() < json.len());
+        let decoded: SignedStatement = serde_cbor::from_slice(&cbor).unwrap();
+        assert_eq!(decoded.to_string(), signed_statement.to_string());
+        assert!(decoded.verify_signatures());
+        assert!(Opinion::from_str("18924;7;0;3;;nokey;AA==").is_err());
     }
 
     #[test]
@@ -295,12 +576,9 @@ mod tests {
         let opinion = example();
         let statement = super::super::statement::tests::example();
         let keypair = super::super::tests::example_keypair();
-        let signed_opinion = opinion.sign_using(&statement.signable_bytes(), &keypair);
+        let signed_opinion = opinion.sign_using(&statement, &keypair);
         let signed_statement_string = format!("{}\n{}", statement, signed_opinion);
-        let signed_statement = SignedStatement {
-            statement: statement,
-            opinions: vec![signed_opinion],
-        };
+        let signed_statement = SignedStatement::new(statement, vec![signed_opinion]);
         assert!(signed_statement.verify_signatures());
         assert_eq!(signed_statement.to_string(), signed_statement_string)
     }
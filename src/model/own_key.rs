@@ -1,31 +1,188 @@
-use libp2p::identity::{Keypair};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+use libp2p::identity::{ed25519, secp256k1, Keypair};
+use rsa::{pkcs8::EncodePrivateKey, rand_core::OsRng, RsaPrivateKey};
 
 use super::{Entity, PublicKey};
 
+/// Size of generated RSA keys in bits
+const RSA_KEY_BITS: usize = 2048;
+
+/// The signature algorithms available for our own key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyAlgorithm {
+    Ed25519,
+    Secp256k1,
+    Rsa,
+}
+
+/// How to obtain our own key when the database does not contain one yet
+#[derive(Clone, Debug)]
+pub enum NewKey {
+    Generate(KeyAlgorithm),
+    Import(KeyAlgorithm, Vec<u8>), // raw secret key, or PKCS#8 document for RSA
+}
+
+#[derive(Debug)]
+pub struct InvalidKey {
+    cause: String,
+}
+
 #[derive(Clone)]
 pub struct OwnKey {
     pub signer: Entity,
-    pub level: u8,
     pub key: Keypair,
+    secret: Vec<u8>, // the secret as stored in the private_key table
 }
 
 impl OwnKey {
-
     // create a new (owner) Trust entry
-    pub fn new() -> Self {
-        let keypair = Keypair::generate_secp256k1();
-        let signer = Entity::Signer(PublicKey{key: keypair.public()});
+    pub fn new(algorithm: KeyAlgorithm) -> Result<Self, InvalidKey> {
+        let keypair = match algorithm {
+            KeyAlgorithm::Ed25519 => Keypair::generate_ed25519(),
+            KeyAlgorithm::Secp256k1 => Keypair::generate_secp256k1(),
+            // libp2p can only read RSA keys, so the key is generated here and read back from PKCS#8
+            KeyAlgorithm::Rsa => return Self::decode(algorithm, &generate_rsa_pkcs8()?),
+        };
+        let secret = match &keypair {
+            Keypair::Ed25519(keypair) => keypair.encode().to_vec(),
+            Keypair::Secp256k1(keypair) => keypair.secret().to_bytes().to_vec(),
+            Keypair::Rsa(_) => unreachable!(),
+        };
+        Ok(Self::with_keypair(keypair, secret))
+    }
+
+    /// Decode a key from its secret bytes: the 64 byte keypair or 32 byte secret for ed25519,
+    /// the 32 byte secret for secp256k1 and a PKCS#8 document for RSA
+    pub fn decode(algorithm: KeyAlgorithm, secret: &[u8]) -> Result<Self, InvalidKey> {
+        let mut bytes = secret.to_vec();
+        let keypair = match algorithm {
+            KeyAlgorithm::Ed25519 if bytes.len() == 32 => {
+                ed25519::SecretKey::from_bytes(&mut bytes).map(|s| Keypair::Ed25519(s.into()))
+            }
+            KeyAlgorithm::Ed25519 => ed25519::Keypair::decode(&mut bytes).map(Keypair::Ed25519),
+            KeyAlgorithm::Secp256k1 => {
+                secp256k1::SecretKey::from_bytes(&mut bytes).map(|s| Keypair::Secp256k1(s.into()))
+            }
+            KeyAlgorithm::Rsa => Keypair::rsa_from_pkcs8(&mut bytes),
+        }
+        .map_err(|e| InvalidKey {
+            cause: e.to_string(),
+        })?;
+        let secret = match &keypair {
+            Keypair::Ed25519(keypair) => keypair.encode().to_vec(),
+            _ => secret.to_vec(),
+        };
+        Ok(Self::with_keypair(keypair, secret))
+    }
+
+    fn with_keypair(keypair: Keypair, secret: Vec<u8>) -> Self {
+        let signer = Entity::Signer(PublicKey {
+            key: keypair.public(),
+        });
         Self {
-            signer: signer,
-            level: 0,
-            key: keypair
+            signer,
+            key: keypair,
+            secret,
         }
     }
 
-    pub fn privkey_string(&self) -> String {
+    pub fn algorithm(&self) -> KeyAlgorithm {
         match &self.key {
-            Keypair::Secp256k1(keypair) => base64::encode(&keypair.secret().to_bytes()),
-            _ => "".into()
+            Keypair::Ed25519(_) => KeyAlgorithm::Ed25519,
+            Keypair::Secp256k1(_) => KeyAlgorithm::Secp256k1,
+            Keypair::Rsa(_) => KeyAlgorithm::Rsa,
+        }
+    }
+
+    pub fn privkey_string(&self) -> String {
+        base64::encode(&self.secret)
+    }
+}
+
+// a new RSA private key as PKCS#8 document
+fn generate_rsa_pkcs8() -> Result<Vec<u8>, InvalidKey> {
+    let invalid = |e: &dyn Display| InvalidKey {
+        cause: e.to_string(),
+    };
+    let key = RsaPrivateKey::new(&mut OsRng, RSA_KEY_BITS).map_err(|e| invalid(&e))?;
+    let document = key.to_pkcs8_der().map_err(|e| invalid(&e))?;
+    Ok(document.as_bytes().to_vec())
+}
+
+impl NewKey {
+    pub fn create(&self) -> Result<OwnKey, InvalidKey> {
+        match self {
+            Self::Generate(algorithm) => OwnKey::new(*algorithm),
+            Self::Import(algorithm, secret) => OwnKey::decode(*algorithm, secret),
+        }
+    }
+}
+
+impl Default for NewKey {
+    fn default() -> Self {
+        Self::Generate(KeyAlgorithm::Secp256k1)
+    }
+}
+
+impl Display for KeyAlgorithm {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::Ed25519 => "ed25519",
+                Self::Secp256k1 => "secp256k1",
+                Self::Rsa => "rsa",
+            }
+        )
+    }
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = InvalidKey;
+    fn from_str(s: &str) -> Result<Self, InvalidKey> {
+        match s {
+            "ed25519" => Ok(Self::Ed25519),
+            "secp256k1" => Ok(Self::Secp256k1),
+            "rsa" => Ok(Self::Rsa),
+            _ => Err(InvalidKey {
+                cause: format!("unknown key algorithm {}", s),
+            }),
         }
     }
-}
\ No newline at end of file
+}
+
+impl Display for InvalidKey {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "invalid key: {}", self.cause)
+    }
+}
+
+impl std::error::Error for InvalidKey {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::{Statement, UnsignedOpinion};
+
+    #[test]
+    fn roundtrip() {
+        for algorithm in [
+            KeyAlgorithm::Ed25519,
+            KeyAlgorithm::Secp256k1,
+            KeyAlgorithm::Rsa,
+        ] {
+            let own_key = OwnKey::new(algorithm).unwrap();
+            let secret = base64::decode(own_key.privkey_string()).unwrap();
+            let decoded = OwnKey::decode(algorithm, &secret).unwrap();
+            assert_eq!(decoded.algorithm(), algorithm);
+            assert_eq!(decoded.signer, own_key.signer);
+            assert!(decoded.signer.to_string().starts_with(&format!("{}:", algorithm)));
+            let statement = Statement::signer(decoded.signer.clone());
+            let opinion = UnsignedOpinion::default().sign_using(&statement, &decoded.key);
+            assert!(opinion.verify_signature(&statement));
+        }
+    }
+}
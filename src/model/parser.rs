@@ -4,17 +4,23 @@ use cidr::{Ipv4Cidr, Ipv6Cidr};
 use nom::{
     self,
     branch::alt,
-    bytes::complete::{is_a, tag},
-    character::complete::{alpha1, alphanumeric1, digit1, space1},
-    combinator::{map, map_res, opt, recognize},
+    bytes::complete::{escaped_transform, is_a, tag, tag_no_case, take_while1},
+    character::complete::{alpha1, alphanumeric1, digit1, none_of, one_of, space0, space1},
+    combinator::{map, map_res, not, opt, recognize, verify},
     error::Error,
     multi::{many0, many1, separated_list1},
-    sequence::{pair, tuple},
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
-use std::str::FromStr;
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
 
-use super::{Entity, EntityType, PublicKey, Statement, Template};
+use super::{
+    template::{Constraint, Parameter, Range},
+    Entity, EntityType, OpinionParameter, PublicKey, Statement, Template,
+};
 
 // nom parser utilities
 fn entity_type(i: &str) -> nom::IResult<&str, EntityType> {
@@ -50,43 +56,119 @@ fn entity_type(i: &str) -> nom::IResult<&str, EntityType> {
 fn entity_type_alternatives(i: &str) -> nom::IResult<&str, Vec<EntityType>> {
     nom::multi::separated_list1(nom::character::complete::char('|'), entity_type)(i)
 }
-fn entity_types(i: &str) -> nom::IResult<&str, Vec<Vec<EntityType>>> {
-    nom::multi::separated_list1(
-        nom::character::complete::char(','),
-        entity_type_alternatives,
+// a comma, optionally surrounded by spaces
+fn separator(i: &str) -> IResult<&str, &str> {
+    delimited(space0, tag(","), space0)(i)
+}
+fn number(i: &str) -> IResult<&str, u32> {
+    map_res(digit1, |s: &str| s.parse::<u32>())(i)
+}
+// an inclusive range like 8..24, 1.., ..24 or a single number. Empty ranges like 24..8 are rejected
+fn range(i: &str) -> IResult<&str, Range> {
+    alt((
+        map(
+            verify(
+                tuple((opt(number), tag(".."), opt(number))),
+                |(min, _, max)| match (min, max) {
+                    (Some(min), Some(max)) => min <= max,
+                    _ => true,
+                },
+            ),
+            |(min, _, max)| Range { min, max },
+        ),
+        map(number, |n| Range {
+            min: Some(n),
+            max: Some(n),
+        }),
+    ))(i)
+}
+fn constraint(i: &str) -> IResult<&str, Constraint> {
+    alt((
+        map(preceded(tag("prefix="), range), Constraint::Prefix),
+        map(preceded(tag("depth="), range), Constraint::Depth),
+        map(preceded(tag("value="), range), Constraint::Value),
+        map(tag("suffix"), |_| Constraint::Suffix),
+    ))(i)
+}
+// a template parameter with optional name and constraints, e.g. `net: IPv4|IPv6[prefix=..24]`
+fn parameter(i: &str) -> IResult<&str, Parameter> {
+    map(
+        tuple((
+            opt(terminated(name, tuple((space0, tag(":"), space0)))),
+            entity_type_alternatives,
+            opt(delimited(
+                tag("["),
+                separated_list1(separator, constraint),
+                tag("]"),
+            )),
+        )),
+        |(name, entity_types, constraints)| Parameter {
+            name: name.map(String::from),
+            entity_types,
+            constraints: constraints.unwrap_or_default(),
+        },
     )(i)
 }
 fn name(i: &str) -> nom::IResult<&str, &str> {
     recognize(pair(alpha1, many0(alt((alpha1, tag("_"))))))(i)
 }
 
-// a domain name label
+// a domain name label, may contain non-ASCII letters and digits
 fn label(i: &str) -> nom::IResult<&str, &str> {
-    recognize(tuple((
-        alphanumeric1,
-        many0(alt((alphanumeric1, tag("-")))),
-        opt(alphanumeric1),
-    )))(i)
+    verify(
+        take_while1(|c: char| c.is_alphanumeric() || c == '-'),
+        |s: &str| !s.starts_with('-'),
+    )(i)
 }
 
-// a domain name
-fn domain(i: &str) -> IResult<&str, Entity> {
-    map(
-        recognize(tuple((many0(tuple((label, tag(".")))), alpha1))),
-        |s| Entity::Domain(s.into()),
+/// Normalize a domain name: lowercase, IDNA labels converted to punycode, without trailing dot
+pub fn normalize_domain(name: &str) -> Result<String, idna::Errors> {
+    idna::domain_to_ascii(name.strip_suffix('.').unwrap_or(name))
+}
+
+/// Convert a normalized domain name to its Unicode representation for display
+pub fn unicode_domain(name: &str) -> String {
+    idna::domain_to_unicode(name).0
+}
+
+// a domain name, the last label must start with a letter. A single trailing root dot is accepted.
+// Top level domains are represented with a trailing dot, other domains without.
+fn domain_name(i: &str) -> IResult<&str, String> {
+    map_res(
+        recognize(pair(
+            verify(separated_list1(tag("."), label), |labels: &Vec<&str>| {
+                labels
+                    .last()
+                    .and_then(|l| l.chars().next())
+                    .is_some_and(char::is_alphabetic)
+            }),
+            opt(tag(".")),
+        )),
+        normalize_domain,
     )(i)
 }
 
+fn domain(i: &str) -> IResult<&str, Entity> {
+    map(domain_name, |name| {
+        if name.contains('.') {
+            Entity::Domain(name)
+        } else {
+            Entity::Domain(name + ".")
+        }
+    })(i)
+}
+
 // localpart - does not handle quoted strings and comments yet
 fn localpart(i: &str) -> IResult<&str, &str> {
     recognize(many1(alt((alphanumeric1, is_a(".!#$%&'*+-/=?^_`{|}~")))))(i)
 }
 
-// an email address
+// an email address, the domain part is normalized
 fn email(i: &str) -> IResult<&str, Entity> {
-    map(recognize(tuple((localpart, tag("@"), domain))), |s| {
-        Entity::EMail(s.into())
-    })(i)
+    map(
+        tuple((localpart, tag("@"), domain_name)),
+        |(localpart, _, domain)| Entity::EMail(format!("{}@{}", localpart, domain)),
+    )(i)
 }
 
 // base64 string - returns matched characters
@@ -101,7 +183,7 @@ fn base64(i: &str) -> IResult<&str, &str> {
 fn hash_value(i: &str) -> IResult<&str, Entity> {
     let (i, _) = tag("#")(i)?;
     alt((
-        map(recognize(email), |s| Entity::hash_string(s)),
+        map(email, |e| Entity::hash_string(&e.to_string())),
         map(base64, |s| Entity::HashValue(s.into())),
     ))(i)
 }
@@ -149,26 +231,91 @@ fn ipv6(i: &str) -> IResult<&str, Entity> {
     )(i)
 }
 
+// the host part of an URL, either a normalized domain name or an IP address
+fn url_host(i: &str) -> IResult<&str, String> {
+    alt((
+        domain_name,
+        map(
+            recognize(map_res(
+                recognize(tuple((digit1, tag("."), digit1, tag("."), digit1, tag("."), digit1))),
+                |s: &str| s.parse::<Ipv4Addr>(),
+            )),
+            String::from,
+        ),
+        map(
+            recognize(delimited(
+                tag("["),
+                map_res(is_a("0123456789ABCDEFabcdef:."), |s: &str| s.parse::<Ipv6Addr>()),
+                tag("]"),
+            )),
+            str::to_ascii_lowercase,
+        ),
+    ))(i)
+}
+
+// an http or https URL - scheme and host are lowercased, a default port is dropped
+fn url(i: &str) -> IResult<&str, Entity> {
+    map(
+        tuple((
+            alt((tag_no_case("https"), tag_no_case("http"))),
+            tag("://"),
+            url_host,
+            opt(preceded(tag(":"), map_res(digit1, |s: &str| s.parse::<u16>()))),
+            recognize(opt(pair(
+                one_of("/?#"),
+                many0(alt((alphanumeric1, is_a("-._~:/?#[]@!$&'*+;=%")))),
+            ))),
+        )),
+        |(scheme, _, host, port, path)| {
+            let scheme = scheme.to_ascii_lowercase();
+            let port = match (scheme.as_str(), port) {
+                ("http", Some(80)) | ("https", Some(443)) | (_, None) => String::new(),
+                (_, Some(port)) => format!(":{}", port),
+            };
+            let path = if path.is_empty() { "/" } else { path };
+            Entity::Url(format!(
+                "{}://{}{}{}",
+                scheme,
+                host,
+                port,
+                path
+            ))
+        },
+    )(i)
+}
+
 pub fn template(i: &str) -> IResult<&str, Entity> {
     map(
-        tuple((name, tag("("), entity_types, tag(")"))),
-        |(name, _, entity_types, _)| {
+        tuple((
+            name,
+            tag("("),
+            space0,
+            separated_list1(separator, parameter),
+            space0,
+            tag(")"),
+        )),
+        |(name, _, _, parameters, _, _)| {
             Entity::Template(Template {
                 name: name.into(),
-                entity_types,
+                parameters,
             })
         },
     )(i)
 }
 
 pub fn signer(i: &str) -> IResult<&str, Entity> {
-    map(recognize(tuple((tag("secp256k1:"), base64))), |s| {
-        Entity::Signer(PublicKey::from_str(&s).expect("public key"))
-    })(i)
+    map_res(
+        recognize(tuple((
+            alt((tag("secp256k1:"), tag("ed25519:"), tag("rsa:"))),
+            base64,
+        ))),
+        |s| PublicKey::from_str(s).map(Entity::Signer),
+    )(i)
 }
 
 pub fn entity(i: &str) -> IResult<&str, Entity> {
-    alt((email, hash_value, template, asn, signer, domain, ipv4, ipv6))(i)
+    // IPv6 must be tried before domain names since an address like fe80::1 starts with a valid label
+    alt((url, email, hash_value, template, asn, signer, ipv6, domain, ipv4))(i)
 }
 
 pub fn statement(i: &str) -> IResult<&str, Statement> {
@@ -182,7 +329,7 @@ pub fn statement(i: &str) -> IResult<&str, Statement> {
             },
         ),
         map(
-            tuple((name, space1, separated_list1(space1, entity))),
+            tuple((name, space1, separated_list1(space1, statement_entity))),
             |(name, _, entities)| Statement {
                 name: name.into(),
                 entities,
@@ -191,9 +338,73 @@ pub fn statement(i: &str) -> IResult<&str, Statement> {
     ))(i)
 }
 
+// in the human-typeable form, a word followed by = starts the opinion parameters
+fn statement_entity(i: &str) -> IResult<&str, Entity> {
+    terminated(entity, not(tag("=")))(i)
+}
+
+fn certainty(i: &str) -> IResult<&str, i8> {
+    verify(
+        map_res(recognize(pair(opt(one_of("+-")), digit1)), i8::from_str),
+        |c| (-3..=3).contains(c),
+    )(i)
+}
+
+// a positive number of days, or of weeks or months with a w or m suffix
+fn days(i: &str) -> IResult<&str, u16> {
+    map_res(
+        pair(digit1, opt(one_of("dwm"))),
+        |(n, unit): (&str, Option<char>)| {
+            let factor = match unit {
+                Some('w') => 7,
+                Some('m') => 30,
+                _ => 1,
+            };
+            u16::from_str(n)
+                .ok()
+                .and_then(|n| n.checked_mul(factor))
+                .filter(|days| *days > 0)
+                .ok_or("duration out of range")
+        },
+    )(i)
+}
+
+// a word, or a double-quoted string in which \" and \\ stand for " and \
+fn comment(i: &str) -> IResult<&str, String> {
+    alt((
+        delimited(
+            tag("\""),
+            map(
+                opt(escaped_transform(none_of("\\\""), '\\', one_of("\\\""))),
+                Option::unwrap_or_default,
+            ),
+            tag("\""),
+        ),
+        map(take_while1(|c: char| !c.is_whitespace()), String::from),
+    ))(i)
+}
+
+fn opinion_parameter(i: &str) -> IResult<&str, OpinionParameter> {
+    alt((
+        map(preceded(tag("certainty="), certainty), OpinionParameter::Certainty),
+        map(preceded(tag("valid="), days), OpinionParameter::Valid),
+        map(preceded(tag("comment="), comment), OpinionParameter::Comment),
+    ))(i)
+}
+
+/// A statement typed by the user, optionally followed by opinion parameters,
+/// e.g. `spammer 192.0.2.1 certainty=-2 valid=1w comment="not really"`
+pub fn statement_input(i: &str) -> IResult<&str, (Statement, Vec<OpinionParameter>)> {
+    terminated(
+        pair(statement, many0(preceded(space1, opinion_parameter))),
+        space0,
+    )(i)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::model::{KeyAlgorithm, OwnKey};
 
     #[test]
     fn email() {
@@ -235,8 +446,58 @@ mod tests {
         // tld, marked by trailing dot
         assert_eq!(
             super::entity("biz").unwrap(),
-            ("", Entity::Domain("biz".into())),
-        )
+            ("", Entity::Domain("biz.".into())),
+        );
+        assert_eq!(
+            super::entity("biz.").unwrap(),
+            ("", Entity::Domain("biz.".into())),
+        );
+    }
+    #[test]
+    fn domain_normalization() {
+        for input in ["Example.COM", "example.com.", "EXAMPLE.com."] {
+            assert_eq!(
+                super::entity(input).unwrap(),
+                ("", Entity::Domain("example.com".into())),
+            );
+        }
+        assert_eq!(
+            super::entity("Bücher.example").unwrap(),
+            ("", Entity::Domain("xn--bcher-kva.example".into())),
+        );
+        assert_eq!(
+            super::entity("user@Bücher.Example.").unwrap(),
+            ("", Entity::EMail("user@xn--bcher-kva.example".into())),
+        );
+        assert_eq!(
+            super::entity("https://Bücher.example/").unwrap(),
+            ("", Entity::Url("https://xn--bcher-kva.example/".into())),
+        );
+        assert_eq!(unicode_domain("xn--bcher-kva.example"), "bücher.example");
+        assert_eq!(
+            super::entity("fe80::1").unwrap(),
+            ("", Entity::IPv6("fe80::1".parse().unwrap())),
+        );
+    }
+    #[test]
+    fn url() {
+        assert_eq!(
+            super::entity("http://Example.COM:80/abuse").unwrap(),
+            ("", Entity::Url("http://example.com/abuse".into())),
+        );
+        assert_eq!(
+            super::entity("https://example.com:8443,").unwrap(),
+            (",", Entity::Url("https://example.com:8443/".into())),
+        );
+        assert!(super::url("ftp://example.com/").is_err());
+    }
+    #[test]
+    fn signer() {
+        let signer = OwnKey::new(KeyAlgorithm::Ed25519).unwrap().signer;
+        let input = signer.to_string();
+        assert!(input.starts_with("ed25519:"));
+        assert_eq!(super::entity(&input).unwrap(), ("", signer));
+        assert!(super::signer("ed25519:AAAA").is_err());
     }
     #[test]
     fn statement() {
@@ -254,4 +515,37 @@ mod tests {
             super::statement("abuse(example.com,abuse@example.com)").unwrap()
         )
     }
+    #[test]
+    fn statement_input() {
+        let (rest, (statement, parameters)) = super::statement_input(
+            r#"spammer 192.0.2.1 certainty=-2 valid=2w comment="says \"hi\"" "#,
+        )
+        .unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(statement.to_string(), "spammer(192.0.2.1)");
+        assert_eq!(
+            parameters,
+            [
+                OpinionParameter::Certainty(-2),
+                OpinionParameter::Valid(14),
+                OpinionParameter::Comment(r#"says "hi""#.into())
+            ]
+        );
+        let (rest, (statement, parameters)) =
+            super::statement_input("spammer(example.com) valid=3 comment=short").unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(statement.to_string(), "spammer(example.com)");
+        assert_eq!(
+            parameters,
+            [
+                OpinionParameter::Valid(3),
+                OpinionParameter::Comment("short".into())
+            ]
+        );
+        let (_, (_, parameters)) = super::statement_input("spammer example.com valid=1m").unwrap();
+        assert_eq!(parameters, [OpinionParameter::Valid(30)]);
+        assert_ne!(super::statement_input("spammer example.com certainty=4").unwrap().0, "");
+        assert_ne!(super::statement_input("spammer example.com valid=9999m").unwrap().0, "");
+        assert_ne!(super::statement_input("spammer example.com valid=0w").unwrap().0, "");
+    }
 }
@@ -0,0 +1,130 @@
+// Public Suffix List handling, see https://publicsuffix.org/list/
+// A snapshot of the list is bundled, it can be replaced at runtime by a more recent local copy.
+
+use std::{collections::HashSet, io, path::Path, sync::RwLock};
+
+use lazy_static::lazy_static;
+use log::info;
+
+use super::parser::normalize_domain;
+
+const BUNDLED: &str = include_str!("public_suffix_list.dat");
+
+#[derive(Debug, Default)]
+pub struct PublicSuffixList {
+    rules: HashSet<String>,      // e.g. co.uk
+    wildcards: HashSet<String>,  // *.ck is stored as ck
+    exceptions: HashSet<String>, // !www.ck is stored as www.ck
+}
+
+lazy_static! {
+    static ref LIST: RwLock<PublicSuffixList> = RwLock::new(PublicSuffixList::parse(BUNDLED));
+}
+
+impl PublicSuffixList {
+    /// parse the text format of the list. Rules are converted to punycode like our domain entities.
+    pub fn parse(text: &str) -> Self {
+        let mut list = Self::default();
+        for line in text.lines() {
+            let rule = match line.split_whitespace().next() {
+                Some(rule) if !rule.starts_with("//") => rule,
+                _ => continue,
+            };
+            let (set, rule) = if let Some(rule) = rule.strip_prefix("!") {
+                (&mut list.exceptions, rule)
+            } else if let Some(rule) = rule.strip_prefix("*.") {
+                (&mut list.wildcards, rule)
+            } else {
+                (&mut list.rules, rule)
+            };
+            if let Ok(rule) = normalize_domain(rule) {
+                set.insert(rule);
+            }
+        }
+        list
+    }
+
+    /// Replace the list used for all lookups by the contents of a local file
+    pub fn load(path: impl AsRef<Path>) -> io::Result<()> {
+        let list = Self::parse(&std::fs::read_to_string(path.as_ref())?);
+        info!(
+            "loaded {} public suffix rules from {}",
+            list.rules.len() + list.wildcards.len() + list.exceptions.len(),
+            path.as_ref().display()
+        );
+        *LIST.write().unwrap() = list;
+        Ok(())
+    }
+
+    /// Number of trailing labels of a normalized domain name which form its public suffix.
+    /// Domains not covered by the list have a single label suffix.
+    fn suffix_labels(&self, domain: &str) -> usize {
+        let labels = domain.trim_end_matches('.').split('.').collect::<Vec<_>>();
+        let n = labels.len();
+        for i in 0..n {
+            let candidate = labels[i..].join(".");
+            if self.exceptions.contains(&candidate) {
+                return n - i - 1;
+            }
+            if self.rules.contains(&candidate)
+                || (i + 1 < n && self.wildcards.contains(&labels[i + 1..].join(".")))
+            {
+                return n - i;
+            }
+        }
+        1
+    }
+
+    /// The registrable domain (public suffix plus one label) of a normalized domain name,
+    /// None if the domain is a public suffix itself
+    fn registrable(&self, domain: &str) -> Option<String> {
+        let labels = domain.trim_end_matches('.').split('.').collect::<Vec<_>>();
+        let suffix = self.suffix_labels(domain);
+        if labels.len() > suffix {
+            Some(labels[labels.len() - suffix - 1..].join("."))
+        } else {
+            None
+        }
+    }
+}
+
+/// The registrable domain of a normalized domain name, using the current list
+pub fn registrable_domain(domain: &str) -> Option<String> {
+    LIST.read().unwrap().registrable(domain)
+}
+
+/// Check whether a normalized domain name is a public suffix, using the current list
+pub fn is_public_suffix(domain: &str) -> bool {
+    registrable_domain(domain).is_none()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registrable() {
+        let list = PublicSuffixList::parse(BUNDLED);
+        assert_eq!(list.registrable("foo.co.uk").as_deref(), Some("foo.co.uk"));
+        assert_eq!(list.registrable("www.foo.co.uk").as_deref(), Some("foo.co.uk"));
+        assert_eq!(list.registrable("co.uk"), None);
+        assert_eq!(list.registrable("uk."), None);
+        assert_eq!(list.registrable("example.com").as_deref(), Some("example.com"));
+        // not in the list, the default rule * applies
+        assert_eq!(list.registrable("a.b.example").as_deref(), Some("b.example"));
+        // wildcard and exception
+        assert_eq!(list.registrable("a.b.ck"), Some("a.b.ck".into()));
+        assert_eq!(list.registrable("b.ck"), None);
+        assert_eq!(list.registrable("www.ck").as_deref(), Some("www.ck"));
+        // Unicode rules are stored as punycode
+        assert_eq!(list.registrable("xn--55qx5d.cn"), None);
+    }
+
+    #[test]
+    fn parse() {
+        let list = PublicSuffixList::parse("// comment\n\nexample\n*.wild.example\n!no.wild.example\n");
+        assert_eq!(list.suffix_labels("foo.example"), 1);
+        assert_eq!(list.suffix_labels("foo.bar.wild.example"), 3);
+        assert_eq!(list.suffix_labels("no.wild.example"), 2);
+    }
+}
@@ -0,0 +1,139 @@
+use std::fmt::{self, Display, Formatter};
+
+use super::{Date, Opinion, PublicKey, UnsignedOpinion};
+
+/// How opinions are combined into a score
+#[derive(Clone, Debug)]
+pub struct ScoreSettings {
+    pub decay: bool,                // if set, opinions lose weight linearly towards their last_date()
+    pub default_trust: Option<f64>, // trust in signers outside our web of trust (0.0..1.0)
+}
+
+/// Combined belief in a statement, computed from all its current opinions.
+/// The value is the sum of the certainties (-3..3) weighted by signer trust and age,
+/// so opposing opinions cancel each other. Opinions with certainty 0 are retractions and don't count.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Score {
+    pub value: f64,
+    pub opinions: usize, // number of opinions which contributed to the value
+}
+
+impl UnsignedOpinion {
+    /// Weight of this opinion on `today`, in range 0.0..1.0.
+    /// Opinions which are expired or not valid yet have weight 0.
+    pub fn weight(&self, today: Date, settings: &ScoreSettings) -> f64 {
+        if today < self.date || self.last_date() < today {
+            0.0
+        } else if settings.decay {
+            let remaining = self.last_date().d - today.d;
+            (remaining + 1) as f64 / (self.valid as f64 + 1.0)
+        } else {
+            1.0
+        }
+    }
+}
+
+impl Score {
+    /// Combine `opinions` as seen on `today`, with `trust` giving the weight of each signer
+    pub fn of<'a>(
+        opinions: impl IntoIterator<Item = &'a Opinion>,
+        today: Date,
+        settings: &ScoreSettings,
+        trust: impl Fn(&PublicKey) -> f64,
+    ) -> Self {
+        let mut score = Self::default();
+        for opinion in opinions {
+            let weight = opinion.weight(today, settings) * trust(&opinion.signer);
+            if weight > 0.0 && opinion.certainty != 0 {
+                score.value += weight * opinion.certainty as f64;
+                score.opinions += 1;
+            }
+        }
+        score
+    }
+
+    /// true if the opinions in favour of the statement outweigh those against it
+    pub fn is_positive(&self) -> bool {
+        self.value > 0.0
+    }
+}
+
+impl Default for ScoreSettings {
+    fn default() -> Self {
+        Self {
+            decay: true,
+            default_trust: None,
+        }
+    }
+}
+
+impl Display for Score {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{:+.2} from {} opinions", self.value, self.opinions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn opinion(keypair: &libp2p::identity::Keypair, date: u32, certainty: i8) -> Opinion {
+        let statement = super::super::statement::tests::example();
+        UnsignedOpinion {
+            date: Date::from(date),
+            valid: 9,
+            serial: 0,
+            certainty,
+            comment: "".into(),
+        }
+        .sign_using(&statement, keypair)
+    }
+
+    #[test]
+    fn weight() {
+        let keypair = super::super::tests::example_keypair();
+        let opinion = opinion(&keypair, 100, 3);
+        let settings = ScoreSettings::default();
+        assert_eq!(opinion.weight(Date::from(99), &settings), 0.0);
+        assert_eq!(opinion.weight(Date::from(100), &settings), 1.0);
+        assert_eq!(opinion.weight(Date::from(105), &settings), 0.5);
+        assert_eq!(opinion.weight(Date::from(109), &settings), 0.1);
+        assert_eq!(opinion.weight(Date::from(110), &settings), 0.0);
+        let settings = ScoreSettings {
+            decay: false,
+            ..settings
+        };
+        assert_eq!(opinion.weight(Date::from(109), &settings), 1.0);
+    }
+
+    #[test]
+    fn combine() {
+        let keypair = super::super::tests::example_keypair();
+        let other = libp2p::identity::Keypair::generate_ed25519();
+        let other_signer = PublicKey {
+            key: other.public(),
+        };
+        let opinions = vec![
+            opinion(&keypair, 100, 3),
+            opinion(&other, 100, -2),
+            opinion(&other, 80, 3), // expired
+            opinion(&other, 100, 0), // retracted
+        ];
+        let settings = ScoreSettings::default();
+        let score = Score::of(&opinions, Date::from(100), &settings, |_| 1.0);
+        assert_eq!(score.value, 1.0);
+        assert_eq!(score.opinions, 2);
+        assert!(score.is_positive());
+
+        let score = Score::of(&opinions, Date::from(100), &settings, |signer| {
+            if *signer == other_signer {
+                0.5
+            } else {
+                0.0
+            }
+        });
+        assert_eq!(score.value, -1.0);
+        assert_eq!(score.opinions, 1);
+        assert!(!score.is_positive());
+    }
+}
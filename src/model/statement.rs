@@ -3,13 +3,14 @@ use std::{
     str::FromStr,
 };
 
+use itertools::Itertools;
 use nom::combinator::all_consuming;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use super::{
     entity::Entity,
     parser,
-    template::{SpecificTemplate, Template},
+    template::{SpecificTemplate, Template, TemplateMismatch},
 };
 
 #[derive(Clone, PartialEq)]
@@ -37,17 +38,23 @@ impl Statement {
         }
     }
 
-    pub fn matches_template(&self, template: &Template) -> bool {
-        self.name == template.name && self.entities.len() == template.entity_types.len() && {
-            for (entity, entity_type_list) in self.entities.iter().zip(template.entity_types.iter())
-            {
-                let entity_type = &entity.entity_type();
-                if !entity_type_list.contains(entity_type) {
-                    return false;
-                }
-            }
-            true
+    /// Check the statement against a template, reporting the first parameter which does not match
+    pub fn matches_template(&self, template: &Template) -> Result<(), TemplateMismatch> {
+        if self.name != template.name {
+            return Err(TemplateMismatch::Name);
+        }
+        if self.entities.len() != template.parameters.len() {
+            return Err(TemplateMismatch::Arity {
+                expected: template.parameters.len(),
+                found: self.entities.len(),
+            });
+        }
+        for (index, (entity, parameter)) in
+            self.entities.iter().zip(template.parameters.iter()).enumerate()
+        {
+            parameter.check(index, entity)?;
         }
+        Ok(())
     }
 
     // create a version of self where literal e-mail addresses are replaced by hashed e-mail addresses
@@ -59,6 +66,15 @@ impl Statement {
         }
     }
 
+    /// Representation for display, with internationalized domain names shown in Unicode
+    pub fn to_unicode(&self) -> String {
+        format!(
+            "{}({})",
+            self.name,
+            self.entities.iter().map(Entity::to_unicode).join(",")
+        )
+    }
+
     /// Return a byte vector for signing
     pub fn signable_bytes(&self) -> Vec<u8> {
         self.to_string().as_bytes().to_vec()
@@ -170,6 +186,43 @@ pub mod tests {
     fn match_template() {
         let stmt = Statement::from_str("abuse(example.com,abuse@example.com)").unwrap();
         let template = Template::from_str("abuse(Domain,EMail|Url)").unwrap();
-        assert!(stmt.matches_template(&template));
+        assert!(stmt.matches_template(&template).is_ok());
+    }
+
+    #[test]
+    fn match_template_constraints() {
+        let template = Template::from_str("asn(net:IPv4[prefix=..24],asn:AS[value=1..])").unwrap();
+        let stmt = Statement::from_str("asn(192.0.2.0/24,AS64496)").unwrap();
+        assert!(stmt.matches_template(&template).is_ok());
+        let stmt = Statement::from_str("asn(192.0.2.0/25,AS64496)").unwrap();
+        assert_eq!(
+            stmt.matches_template(&template).unwrap_err().to_string(),
+            "parameter net violates constraint prefix=..24"
+        );
+        let stmt = Statement::from_str("asn(192.0.2.0/24,AS0)").unwrap();
+        assert_eq!(
+            stmt.matches_template(&template).unwrap_err().to_string(),
+            "parameter asn violates constraint value=1.."
+        );
+        let stmt = Statement::from_str("asn(example.com,AS1)").unwrap();
+        assert_eq!(
+            stmt.matches_template(&template).unwrap_err().to_string(),
+            "parameter net does not accept Domain"
+        );
+    }
+
+    #[test]
+    fn match_template_public_suffix() {
+        let template = Template::from_str("spammer(Domain)").unwrap();
+        let stmt = Statement::from_str("spammer(example.co.uk)").unwrap();
+        assert!(stmt.matches_template(&template).is_ok());
+        let stmt = Statement::from_str("spammer(co.uk)").unwrap();
+        assert_eq!(
+            stmt.matches_template(&template).unwrap_err().to_string(),
+            "parameter #1 does not allow public suffix co.uk"
+        );
+        let template = Template::from_str("registry(Domain[suffix])").unwrap();
+        let stmt = Statement::from_str("registry(co.uk)").unwrap();
+        assert!(stmt.matches_template(&template).is_ok());
     }
 }
@@ -6,10 +6,47 @@ use super::parser;
 
 use super::entity::{Entity, EntityType};
 
+/// A statement template: a name and a list of parameters, each accepting one of a set of entity types.
+/// Parameters may be named and carry constraints on the values they accept, for example
+/// `asn(net: IPv4[prefix=..24], asn: AS[value=1..])`
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct Template {
     pub name: String,
-    pub entity_types: Vec<Vec<EntityType>>,
+    pub parameters: Vec<Parameter>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Parameter {
+    pub name: Option<String>,
+    pub entity_types: Vec<EntityType>,
+    pub constraints: Vec<Constraint>,
+}
+
+/// A constraint on the value of a template parameter. Constraints only apply to the entity types for which
+/// they make sense, e.g. a prefix constraint does not restrict a Domain alternative of the same parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Constraint {
+    Prefix(Range), // prefix length of an IPv4 or IPv6 address range
+    Depth(Range),  // number of labels of a domain, also of the domain part of e-mail addresses and URLs
+    Value(Range),  // number of an autonomous system
+    Suffix,        // a domain may be a public suffix like co.uk, which is rejected otherwise
+}
+
+/// An inclusive range of numbers, open on either side
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Range {
+    pub min: Option<u32>,
+    pub max: Option<u32>,
+}
+
+/// The reason why a statement does not match a template
+#[derive(Debug, PartialEq)]
+pub enum TemplateMismatch {
+    Name,
+    Arity { expected: usize, found: usize },
+    EntityType { parameter: String, found: EntityType },
+    Constraint { parameter: String, constraint: Constraint },
+    PublicSuffix { parameter: String, found: String },
 }
 
 pub struct SpecificTemplate {
@@ -17,6 +54,84 @@ pub struct SpecificTemplate {
     pub entity_types: Vec<EntityType>,
 }
 
+impl Template {
+    /// create a template with unnamed and unconstrained parameters
+    #[cfg(test)]
+    pub fn new(name: &str, entity_types: Vec<Vec<EntityType>>) -> Self {
+        Self {
+            name: name.into(),
+            parameters: entity_types
+                .into_iter()
+                .map(|entity_types| Parameter {
+                    name: None,
+                    entity_types,
+                    constraints: vec![],
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Parameter {
+    /// a description of the parameter for error messages: its name if it has one, otherwise its position
+    pub fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("#{}", index + 1),
+        }
+    }
+
+    /// check whether the entity is acceptable for this parameter
+    pub fn check(&self, index: usize, entity: &Entity) -> Result<(), TemplateMismatch> {
+        let entity_type = entity.entity_type();
+        if !self.entity_types.contains(&entity_type) {
+            return Err(TemplateMismatch::EntityType {
+                parameter: self.label(index),
+                found: entity_type,
+            });
+        }
+        if entity.is_public_suffix() && !self.constraints.contains(&Constraint::Suffix) {
+            return Err(TemplateMismatch::PublicSuffix {
+                parameter: self.label(index),
+                found: entity.to_string(),
+            });
+        }
+        match self.constraints.iter().find(|c| !c.allows(entity)) {
+            Some(constraint) => Err(TemplateMismatch::Constraint {
+                parameter: self.label(index),
+                constraint: *constraint,
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Constraint {
+    pub fn allows(&self, entity: &Entity) -> bool {
+        match (self, entity) {
+            (Self::Prefix(range), Entity::IPv4(cidr)) => range.contains(cidr.network_length() as u32),
+            (Self::Prefix(range), Entity::IPv6(cidr)) => range.contains(cidr.network_length() as u32),
+            (Self::Depth(range), Entity::Domain(domain)) => {
+                range.contains(domain.trim_end_matches('.').split('.').count() as u32)
+            }
+            (Self::Depth(_), Entity::EMail(_)) | (Self::Depth(_), Entity::Url(_)) => {
+                match entity.domain() {
+                    Some(domain) => self.allows(&domain),
+                    None => true,
+                }
+            }
+            (Self::Value(range), Entity::AS(asn)) => range.contains(*asn),
+            _ => true,
+        }
+    }
+}
+
+impl Range {
+    pub fn contains(&self, value: u32) -> bool {
+        self.min.is_none_or(|min| min <= value) && self.max.is_none_or(|max| value <= max)
+    }
+}
+
 impl Display for Template {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -24,21 +139,96 @@ impl Display for Template {
             "{}({})",
             self.name,
             &self
-                .entity_types
+                .parameters
                 .iter()
-                .map(|x| {
-                    (&x).iter()
-                        .map(EntityType::to_string)
-                        .collect::<Vec<String>>()
-                        .join("|")
-                        .to_string()
-                })
+                .map(Parameter::to_string)
                 .collect::<Vec<String>>()
                 .join(",")
         )
     }
 }
 
+impl Display for Parameter {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}:", name)?;
+        }
+        write!(
+            f,
+            "{}",
+            self.entity_types
+                .iter()
+                .map(EntityType::to_string)
+                .collect::<Vec<String>>()
+                .join("|")
+        )?;
+        if !self.constraints.is_empty() {
+            write!(
+                f,
+                "[{}]",
+                self.constraints
+                    .iter()
+                    .map(Constraint::to_string)
+                    .collect::<Vec<String>>()
+                    .join(",")
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for Constraint {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Prefix(range) => write!(f, "prefix={}", range),
+            Self::Depth(range) => write!(f, "depth={}", range),
+            Self::Value(range) => write!(f, "value={}", range),
+            Self::Suffix => write!(f, "suffix"),
+        }
+    }
+}
+
+impl Display for Range {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min == max => write!(f, "{}", min),
+            (min, max) => {
+                if let Some(min) = min {
+                    write!(f, "{}", min)?;
+                }
+                write!(f, "..")?;
+                if let Some(max) = max {
+                    write!(f, "{}", max)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Display for TemplateMismatch {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Name => write!(f, "different name"),
+            Self::Arity { expected, found } => {
+                write!(f, "expected {} entities, found {}", expected, found)
+            }
+            Self::EntityType { parameter, found } => {
+                write!(f, "parameter {} does not accept {}", parameter, found)
+            }
+            Self::Constraint {
+                parameter,
+                constraint,
+            } => write!(f, "parameter {} violates constraint {}", parameter, constraint),
+            Self::PublicSuffix { parameter, found } => write!(
+                f,
+                "parameter {} does not allow public suffix {}",
+                parameter, found
+            ),
+        }
+    }
+}
+
 impl Display for SpecificTemplate {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
         write!(
@@ -80,19 +270,13 @@ mod tests {
 
     #[test]
     fn display() {
-        let template = Template {
-            name: "template".into(),
-            entity_types: vec![vec![EntityType::Template]],
-        };
+        let template = Template::new("template", vec![vec![EntityType::Template]]);
         assert_eq!(template.to_string(), "template(Template)");
     }
 
     #[test]
     fn from_str() {
-        let template = Template {
-            name: "template".into(),
-            entity_types: vec![vec![EntityType::Template]],
-        };
+        let template = Template::new("template", vec![vec![EntityType::Template]]);
         assert_eq!(template, Template::from_str("template(Template)").unwrap())
     }
 
@@ -102,12 +286,54 @@ mod tests {
         let template: Template = Template::from_str(input).unwrap();
         assert_eq!(template.name, "spammer");
         assert_eq!(
-            template.entity_types,
-            vec![vec![
-                EntityType::HashValue,
-                EntityType::IPv4,
-                EntityType::IPv6
-            ]]
+            template,
+            Template::new(
+                "spammer",
+                vec![vec![
+                    EntityType::HashValue,
+                    EntityType::IPv4,
+                    EntityType::IPv6
+                ]]
+            )
+        );
+    }
+
+    #[test]
+    fn from_str_named() {
+        let template = Template::from_str("abuse(subject: Domain, contact: EMail|Url)").unwrap();
+        assert_eq!(template.parameters[0].name, Some("subject".into()));
+        assert_eq!(
+            template.parameters[1].entity_types,
+            vec![EntityType::EMail, EntityType::Url]
+        );
+        assert_eq!(template.to_string(), "abuse(subject:Domain,contact:EMail|Url)");
+    }
+
+    #[test]
+    fn from_str_constraints() {
+        let input = "asn(net:IPv4[prefix=..24],asn:AS[value=1..],Domain[depth=2],Domain[suffix])";
+        let template = Template::from_str(input).unwrap();
+        assert_eq!(
+            template.parameters[0].constraints,
+            vec![Constraint::Prefix(Range {
+                min: None,
+                max: Some(24)
+            })]
         );
+        assert_eq!(
+            template.parameters[2].constraints,
+            vec![Constraint::Depth(Range {
+                min: Some(2),
+                max: Some(2)
+            })]
+        );
+        assert_eq!(template.parameters[3].constraints, vec![Constraint::Suffix]);
+        assert_eq!(template.to_string(), input);
+    }
+
+    #[test]
+    fn from_str_empty_range() {
+        assert!(Template::from_str("net(IPv4[prefix=24..8])").is_err());
+        assert!(Template::from_str("net(IPv4[prefix=8..8])").is_ok());
     }
 }
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use super::PublicKey;
+
+/// How trust propagates from our own key through `trusts(Signer,Signer)` statements
+#[derive(Clone, Debug)]
+pub struct TrustSettings {
+    pub depth: u8,        // maximum number of hops from our own key
+    pub attenuation: f64, // factor applied for each hop after the first (0.0..1.0)
+    pub threshold: f64,   // signers with less trust are ignored
+}
+
+/// A `trusts(truster,trusted)` statement with the certainty of the truster's opinion on it
+#[derive(Clone, Debug)]
+pub struct TrustEdge {
+    pub truster: PublicKey,
+    pub trusted: PublicKey,
+    pub certainty: i8,
+}
+
+/// Compute the effective trust (0.0..1.0) in every signer reachable from `own` within `settings.depth` hops.
+/// Our own key has trust 1.0. A signer directly trusted by us with certainty c gets c/3,
+/// every further hop multiplies the truster's trust with c/3 and the attenuation; the best path wins.
+/// Negative certainties are not propagated, but a signer we distrust directly is never trusted.
+pub fn compute_trust(
+    own: &PublicKey,
+    edges: &[TrustEdge],
+    settings: &TrustSettings,
+) -> HashMap<PublicKey, f64> {
+    let mut trust = HashMap::new();
+    trust.insert(own.clone(), 1.0);
+    let distrusted = edges
+        .iter()
+        .filter(|edge| edge.truster == *own && edge.certainty < 0)
+        .map(|edge| &edge.trusted)
+        .collect::<Vec<_>>();
+    let mut frontier = vec![own.clone()];
+    for hop in 0..settings.depth {
+        let factor = if hop == 0 { 1.0 } else { settings.attenuation };
+        let previous = trust.clone(); // only extend paths with at most `hop` hops
+        let mut next = vec![];
+        for truster in &frontier {
+            let truster_trust = previous[truster];
+            for edge in edges
+                .iter()
+                .filter(|edge| edge.truster == *truster && edge.certainty > 0)
+            {
+                if edge.trusted == *own || distrusted.contains(&&edge.trusted) {
+                    continue;
+                }
+                let value = truster_trust * factor * edge.certainty as f64 / 3.0;
+                let known = trust.get(&edge.trusted).copied().unwrap_or(0.0);
+                if value > known {
+                    trust.insert(edge.trusted.clone(), value);
+                    if !next.contains(&edge.trusted) {
+                        next.push(edge.trusted.clone());
+                    }
+                }
+            }
+        }
+        frontier = next;
+    }
+    for signer in distrusted {
+        trust.insert(signer.clone(), 0.0);
+    }
+    trust
+}
+
+impl Default for TrustSettings {
+    fn default() -> Self {
+        Self {
+            depth: 3,
+            attenuation: 0.5,
+            threshold: 0.1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key() -> PublicKey {
+        PublicKey {
+            key: libp2p::identity::Keypair::generate_ed25519().public(),
+        }
+    }
+
+    fn edge(truster: &PublicKey, trusted: &PublicKey, certainty: i8) -> TrustEdge {
+        TrustEdge {
+            truster: truster.clone(),
+            trusted: trusted.clone(),
+            certainty,
+        }
+    }
+
+    #[test]
+    fn transitive() {
+        let (own, a, b, c, d, e) = (key(), key(), key(), key(), key(), key());
+        let edges = vec![
+            edge(&own, &a, 3),
+            edge(&own, &b, 1),
+            edge(&a, &b, 3),
+            edge(&b, &c, 3),
+            edge(&c, &d, 3),
+            edge(&d, &e, 3), // too far away
+        ];
+        let trust = compute_trust(&own, &edges, &TrustSettings::default());
+        assert_eq!(trust[&own], 1.0);
+        assert_eq!(trust[&a], 1.0);
+        assert_eq!(trust[&b], 0.5);
+        assert_eq!(trust[&c], 0.25);
+        assert!((trust[&d] - 1.0 / 12.0).abs() < 1e-9);
+        assert!(!trust.contains_key(&e));
+    }
+
+    #[test]
+    fn distrust() {
+        let (own, a, b) = (key(), key(), key());
+        let edges = vec![
+            edge(&own, &a, 3),
+            edge(&a, &b, 3),
+            edge(&own, &b, -1),
+            edge(&a, &own, -3), // can't reduce our own trust
+        ];
+        let trust = compute_trust(&own, &edges, &TrustSettings::default());
+        assert_eq!(trust[&own], 1.0);
+        assert_eq!(trust[&a], 1.0);
+        assert_eq!(trust[&b], 0.0);
+    }
+}
@@ -0,0 +1,221 @@
+use chrono::{TimeZone, Utc};
+use libp2p::{
+    identify::IdentifyEvent,
+    kad::{KademliaEvent, QueryResult},
+    mdns::MdnsEvent,
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use log::{debug, error, info, warn};
+use serde_json::json;
+
+use crate::console::Table;
+
+use super::{Message, ReputationNet, RpcProtocol};
+
+// Peer discovery: peers are found via mDNS on the local network segment and via a Kademlia DHT,
+// starting from configured bootstrap peers. Peers on the local network segment and bootstrap peers become
+// explicit gossipsub peers, peers found in the DHT are dialed and join the gossipsub mesh like any other peer.
+// Peers we have been connected to are remembered in the database and redialed after a restart.
+
+/// version of the Kademlia protocol of the reputation network, which is separate from the public IPFS DHT
+pub const KADEMLIA_VERSION: &str = "kad/1.0.0";
+
+/// Settings for finding peers
+#[derive(Clone, Debug)]
+pub struct DiscoverySettings {
+    pub bootstrap: Vec<Multiaddr>, // addresses of well-known peers, ending in /p2p/<peer id>
+    pub mdns: bool,                // look for peers on the local network segment
+}
+
+impl Default for DiscoverySettings {
+    fn default() -> Self {
+        Self {
+            bootstrap: vec![],
+            mdns: true,
+        }
+    }
+}
+
+/// Split a multiaddress ending in /p2p/<peer id> into the peer id and the address to dial
+pub fn split_peer_address(address: &Multiaddr) -> Option<(PeerId, Multiaddr)> {
+    let mut address = address.clone();
+    match address.pop() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash)
+            .ok()
+            .map(|peer| (peer, address)),
+        _ => None,
+    }
+}
+
+impl ReputationNet {
+    /// Add the bootstrap peers to the DHT and start looking for our neighbours
+    pub fn bootstrap(&mut self, addresses: &[Multiaddr]) {
+        for address in addresses {
+            match split_peer_address(address) {
+                Some((peer, address)) => {
+                    self.kademlia.add_address(&peer, address.clone());
+                    self.add_explicit_peer(&peer, Some(address));
+                    self.bootstrap_peers.insert(peer);
+                }
+                None => warn!(
+                    "bootstrap address {} lacks a /p2p/<peer id> suffix",
+                    address
+                ),
+            }
+        }
+        if let Err(e) = self.kademlia.bootstrap() {
+            debug!("could not bootstrap: {:?}", e);
+        }
+    }
+
+    /// Look for the peers closest to a random id, which fills our routing table over time
+    pub fn random_walk(&mut self) {
+        self.kademlia.get_closest_peers(PeerId::random());
+    }
+
+    /// Always exchange gossip with a peer on the local network segment or a bootstrap peer
+    fn add_explicit_peer(&mut self, peer: &PeerId, addresses: impl IntoIterator<Item = Multiaddr>) {
+        if *peer == self.local_peer_id() {
+            return;
+        }
+        for address in addresses {
+            self.rpc.add_address(peer, address);
+        }
+        self.gossipsub.add_explicit_peer(peer);
+    }
+
+    fn is_explicit_peer(&self, peer: &PeerId) -> bool {
+        let discovered_via_mdns = match self.mdns.as_ref() {
+            Some(mdns) => mdns.has_node(peer),
+            None => false,
+        };
+        discovered_via_mdns || self.bootstrap_peers.contains(peer)
+    }
+
+    /// Peers found in the DHT since the last call, to be dialed by the swarm unless already connected
+    pub fn take_dial_candidates(&mut self) -> Vec<(PeerId, Vec<Multiaddr>)> {
+        std::mem::take(&mut self.dial_candidates)
+    }
+
+    pub fn handle_mdns_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(list) => {
+                for (peer, address) in list {
+                    self.add_explicit_peer(&peer, Some(address));
+                }
+            }
+            MdnsEvent::Expired(list) => {
+                for (peer, _addr) in list {
+                    if !self.is_explicit_peer(&peer) {
+                        self.gossipsub.remove_explicit_peer(&peer);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn handle_kademlia_event(&mut self, event: KademliaEvent) {
+        match event {
+            KademliaEvent::RoutingUpdated {
+                peer, addresses, ..
+            } => {
+                info!("discovered peer {} via DHT", peer);
+                if peer != self.local_peer_id() {
+                    self.dial_candidates.push((peer, addresses.into_vec()));
+                }
+            }
+            KademliaEvent::OutboundQueryCompleted {
+                result: QueryResult::GetClosestPeers(result),
+                ..
+            } => match result {
+                Ok(ok) => debug!("random walk found {} peers", ok.peers.len()),
+                Err(e) => debug!("random walk failed: {:?}", e),
+            },
+            KademliaEvent::OutboundQueryCompleted {
+                result: QueryResult::Bootstrap(Err(e)),
+                ..
+            } => warn!("bootstrap failed: {:?}", e),
+            _ => (),
+        }
+    }
+
+    /// Peers tell us their listen addresses, which we add to the DHT if they take part in it.
+    /// Peers of other networks (mDNS does not know about them) are not used any further.
+    pub fn handle_identify_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received { peer_id, info } = event {
+            // whatever the peer supports, it is no version 1 node waiting for the identify timeout
+            self.unidentified.remove(&peer_id);
+            if info.protocol_version != self.network_id.protocol("1.0") {
+                info!(
+                    "ignoring peer {} of another network ({})",
+                    peer_id, info.protocol_version
+                );
+                self.gossipsub.remove_explicit_peer(&peer_id);
+                self.sync_state.remove_peer(&peer_id);
+                return;
+            }
+            match RpcProtocol::negotiated(&self.network_id, &info.protocols) {
+                Some(version) => self.peer_identified(peer_id, version),
+                None => info!("peer {} supports none of our RPC versions", peer_id),
+            }
+            let kademlia_protocol = self.network_id.protocol(KADEMLIA_VERSION);
+            if info.protocols.contains(&kademlia_protocol) {
+                for address in &info.listen_addrs {
+                    self.kademlia.add_address(&peer_id, address.clone());
+                }
+            }
+            self.peer_seen(peer_id, info.listen_addrs);
+        }
+    }
+
+    /// Queue a peer to be remembered in the database, the actual update happens in `remember_peer`
+    pub fn peer_seen(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        let message = Message::PeerSeen { peer_id, addresses };
+        if let Err(e) = self.event_sender.try_send(message) {
+            error!("could not send event: {:?}", e);
+        }
+    }
+
+    pub async fn remember_peer(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>) {
+        if let Err(e) = self
+            .storage
+            .read()
+            .await
+            .remember_peer(&peer_id, &addresses)
+            .await
+        {
+            error!("could not remember peer {}: {:?}", peer_id, e);
+        }
+    }
+
+    /// Show the peers remembered in the database, marking those currently connected
+    pub async fn print_known_peers(&mut self) {
+        let peers = match self.storage.read().await.list_peers().await {
+            Ok(peers) => peers,
+            Err(e) => return error!("could not list peers: {:?}", e),
+        };
+        let format_time = |t: i64| Utc.timestamp(t, 0).format("%Y-%m-%d %H:%M:%S").to_string();
+        let mut table = Table::new(&[
+            "peer",
+            "connected",
+            "last seen",
+            "synced rounds",
+            "last synced",
+            "addresses",
+        ]);
+        for peer in peers {
+            let connected = self.sync_state.progress().any(|(p, _)| *p == peer.peer_id);
+            let addresses = peer.addresses.iter().map(|a| a.to_string());
+            table.push(vec![
+                json!(peer.peer_id.to_string()),
+                json!(connected),
+                json!(format_time(peer.last_seen)),
+                json!(peer.synced_rounds),
+                json!(peer.last_synced.map(format_time)),
+                json!(addresses.collect::<Vec<_>>()),
+            ]);
+        }
+        println!("{}", table.render(self.output));
+    }
+}
@@ -0,0 +1,89 @@
+use std::time::Duration;
+
+use log::{error, info};
+
+use crate::{model::Date, scheduler::Job};
+
+use super::ReputationNet;
+
+// Periodic jobs: the scheduler decides when they are due, the network loop runs them here.
+// A failing job is logged and will simply run again after its interval.
+
+/// Settings for the maintenance jobs
+#[derive(Clone, Debug)]
+pub struct MaintenanceSettings {
+    pub peer_expiry: Duration, // peers not seen for this long are forgotten
+    pub announce_days: u32,    // number of days up to today whose sync infos are announced
+}
+
+impl Default for MaintenanceSettings {
+    fn default() -> Self {
+        Self {
+            peer_expiry: Duration::from_secs(30 * 86400),
+            announce_days: 2,
+        }
+    }
+}
+
+impl ReputationNet {
+    pub fn set_maintenance_settings(&mut self, settings: MaintenanceSettings) {
+        self.maintenance = settings;
+    }
+
+    pub async fn run_job(&mut self, job: Job) {
+        info!("running job {:?}", job);
+        match job {
+            Job::Cleanup => self.cleanup().await,
+            Job::Refresh => self.refresh_opinions().await,
+            Job::Announce => self.announce_recent_infos().await,
+            Job::CatchUp => self.schedule_catch_up().await,
+            Job::RandomWalk => self.random_walk(),
+        }
+    }
+
+    /// Remove expired opinions and the statements only they referred to, and forget stale peers
+    async fn cleanup(&mut self) {
+        {
+            let mut storage = self.storage.write().await;
+            if let Err(e) = storage.cleanup().await {
+                error!("could not clean up the database: {:?}", e);
+            }
+            // expired trusts() opinions change the web of trust
+            if let Err(e) = storage.update_trust().await {
+                error!("could not update trust: {:?}", e);
+            }
+            match storage.expire_peers(self.maintenance.peer_expiry).await {
+                Ok(0) => (),
+                Ok(expired) => info!("forgot {} stale peers", expired),
+                Err(e) => error!("could not expire peers: {:?}", e),
+            }
+        }
+        self.sync_state.flush_own_infos();
+    }
+
+    /// Re-sign our own opinions which are about to expire and publish them
+    async fn refresh_opinions(&mut self) {
+        let refreshed = self.storage.write().await.refresh_opinions().await;
+        match refreshed {
+            Ok(statements) => {
+                if statements.is_empty() {
+                    return;
+                }
+                info!("refreshed {} opinions", statements.len());
+                self.sync_state.flush_own_infos();
+                for signed_statement in statements {
+                    self.publish_statement(signed_statement);
+                }
+            }
+            Err(e) => error!("could not refresh opinions: {:?}", e),
+        }
+    }
+
+    /// Announce our sync infos for the most recent days, so that peers which differ can reconcile with us
+    async fn announce_recent_infos(&mut self) {
+        let today = Date::today().d;
+        for date in (0..self.maintenance.announce_days).filter_map(|i| today.checked_sub(i)) {
+            self.announce_infos(Date::from(date)).await;
+        }
+    }
+}
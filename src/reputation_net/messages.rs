@@ -1,10 +1,13 @@
-use libp2p::gossipsub::TopicHash;
-use libp2p::{request_response::ResponseChannel, PeerId};
+use libp2p::gossipsub::{MessageId, TopicHash};
+use libp2p::{
+    request_response::{RequestId, ResponseChannel},
+    Multiaddr, PeerId,
+};
 use serde::{Deserialize, Serialize};
 
 use crate::model::{Date, SignedStatement};
 
-use crate::storage::SyncInfos;
+use crate::storage::{SyncInfos, SyncRange};
 
 /// Broadcast messages are sent using GossipSub to all peers in the network
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,6 +21,9 @@ pub enum BroadcastMessage {
 pub enum RpcRequest {
     TemplateRequest,
     OpinionRequest { name: String, date: Date },
+    Reconcile(Reconcile),
+    SyncInfos(SyncInfos),
+    TemplatePage { after: i64, limit: usize }, // templates with statement ids greater than `after`
 }
 
 /// Rpc responses are only sent in response to rpc requests
@@ -25,6 +31,25 @@ pub enum RpcRequest {
 pub enum RpcResponse {
     None,
     Statements(Vec<SignedStatement>),
+    Reconcile(Reconcile),
+    SyncInfos(SyncInfos),
+    Page {
+        statements: Vec<SignedStatement>,
+        next: Option<Box<RpcRequest>>, // the request for the next page, if there is one
+    },
+}
+
+/// One step of the set reconciliation of the opinions with a given date on statements with a given name.
+/// Both peers answer each other's steps until no differing ranges and wanted opinions remain.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reconcile {
+    pub name: String,
+    pub date: Date,
+    pub ranges: Vec<SyncRange>, // ranges the receiver should compare with its own opinions
+    pub want: Vec<String>,      // signatures of opinions the sender wants to receive
+    pub statements: Vec<SignedStatement>, // opinions the receiver does not have
+    #[serde(default)]
+    pub more: Vec<String>, // signatures of further opinions the receiver does not have, to be requested
 }
 
 /// This enum is used to communicate broadcast and rpc messages from the receiving NetworkBehaviour to the central dispatch
@@ -33,7 +58,9 @@ pub enum Message {
     Broadcast {
         peer_id: PeerId,
         message: BroadcastMessage,
-        topic: TopicHash
+        topic: TopicHash,
+        message_id: MessageId,
+        propagation_source: PeerId, // the peer which forwarded the message to us
     },
     Request {
         peer_id: PeerId,
@@ -42,6 +69,14 @@ pub enum Message {
     },
     Response {
         peer_id: PeerId,
+        request_id: RequestId,
         response: RpcResponse,
     },
+    PeerSeen {
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>, // addresses under which the peer can be dialed
+    },
+    IdentifyTimeout {
+        peer_id: PeerId, // a connected peer which may not have sent its identify info yet
+    },
 }
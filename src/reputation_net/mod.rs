@@ -1,43 +1,80 @@
-use std::{collections::HashSet, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
 
-use async_std::sync::RwLock;
+use async_std::{
+    sync::RwLock,
+    task::{sleep, spawn},
+};
 
 use futures::channel::mpsc::Sender;
-use libp2p::{gossipsub::TopicHash, request_response::ResponseChannel};
-use log::{error, info};
+use libp2p::{
+    gossipsub::TopicHash,
+    request_response::{RequestId, ResponseChannel},
+};
+use log::{debug, error, info, warn};
 
 use libp2p::{
-    gossipsub::{Gossipsub, GossipsubConfig, GossipsubEvent, IdentTopic, MessageAuthenticity},
+    core::ConnectedPoint,
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, IdentTopic, MessageAcceptance,
+        MessageAuthenticity, MessageId,
+    },
+    identify::{Identify, IdentifyConfig, IdentifyEvent},
     identity::Keypair,
+    kad::{store::MemoryStore, Kademlia, KademliaConfig, KademliaEvent},
     mdns::{Mdns, MdnsConfig, MdnsEvent},
     ping::{Ping, PingConfig, PingEvent},
     request_response::{
         ProtocolSupport, RequestResponse, RequestResponseConfig, RequestResponseEvent,
         RequestResponseMessage,
     },
-    NetworkBehaviour, PeerId,
+    swarm::toggle::Toggle,
+    Multiaddr, NetworkBehaviour, PeerId,
 };
 
 use crate::{
-    model::Date,
-    storage::{PersistResult, Repository},
+    console::OutputFormat,
+    model::{Date, NewKey},
+    storage::{Id, PersistResult},
 };
 
-use super::model::{Entity, SignedStatement, Statement, UnsignedOpinion};
+use super::model::{Entity, OpinionParameter, SignedStatement, Statement, UnsignedOpinion};
 use super::storage::Storage;
 
+mod discovery;
+mod jobs;
 mod messages;
+mod network_id;
+mod peer_score;
 mod rpc;
 mod sync;
+mod transport;
 mod user_input;
+mod validation;
+pub use discovery::DiscoverySettings;
+use discovery::*;
+pub use jobs::MaintenanceSettings;
 pub use messages::*;
+pub use network_id::NetworkId;
+use peer_score::*;
 use rpc::*;
+pub use rpc::RpcLimits;
+pub use sync::SyncSettings;
 use sync::*;
+pub use transport::{build_transport, read_swarm_key, resolve_listen_address, TransportSettings};
+
+/// how long to wait for the identify info of a new peer before taking it for a version 1 node
+const IDENTIFY_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "OutEvent")]
 pub struct ReputationNet {
-    mdns: Mdns,
+    mdns: Toggle<Mdns>,
+    kademlia: Kademlia<MemoryStore>,
+    identify: Identify,
     gossipsub: Gossipsub,
     ping: Ping,
     rpc: RequestResponse<RpcCodec>,
@@ -50,11 +87,33 @@ pub struct ReputationNet {
     pub local_key: Keypair,
     #[behaviour(ignore)]
     sync_state: SyncState,
+    #[behaviour(ignore)]
+    penalties: PeerPenalties,
+    #[behaviour(ignore)]
+    rpc_limits: RpcLimits,
+    #[behaviour(ignore)]
+    rpc_versions: HashMap<PeerId, RpcVersion>, // RPC versions of connected peers, learned via identify
+    #[behaviour(ignore)]
+    unidentified: HashSet<PeerId>, // connected peers which have not sent their identify info yet
+    #[behaviour(ignore)]
+    network_id: NetworkId,
+    #[behaviour(ignore)]
+    bootstrap_peers: HashSet<PeerId>,
+    #[behaviour(ignore)]
+    dial_candidates: Vec<(PeerId, Vec<Multiaddr>)>, // peers found in the DHT, see take_dial_candidates
+    #[behaviour(ignore)]
+    maintenance: MaintenanceSettings,
+    #[behaviour(ignore)]
+    pub unicode: bool, // show internationalized domain names in Unicode instead of punycode
+    #[behaviour(ignore)]
+    pub output: OutputFormat, // how listings are shown on the console
 }
 
 #[derive(Debug)]
 pub enum OutEvent {
     Mdns(MdnsEvent),
+    Kademlia(KademliaEvent),
+    Identify(IdentifyEvent),
     Gossipsub(GossipsubEvent),
     Ping(PingEvent),
     Rpc(RequestResponseEvent<RpcRequest, RpcResponse>),
@@ -66,6 +125,18 @@ impl From<MdnsEvent> for OutEvent {
     }
 }
 
+impl From<KademliaEvent> for OutEvent {
+    fn from(v: KademliaEvent) -> Self {
+        Self::Kademlia(v)
+    }
+}
+
+impl From<IdentifyEvent> for OutEvent {
+    fn from(v: IdentifyEvent) -> Self {
+        Self::Identify(v)
+    }
+}
+
 impl From<GossipsubEvent> for OutEvent {
     fn from(v: GossipsubEvent) -> Self {
         Self::Gossipsub(v)
@@ -84,48 +155,113 @@ impl From<RequestResponseEvent<RpcRequest, RpcResponse>> for OutEvent {
     }
 }
 
+/// Settings of the network behaviour
+#[derive(Clone, Debug, Default)]
+pub struct NetworkSettings {
+    pub rpc_limits: RpcLimits,
+    pub discovery: DiscoverySettings,
+    pub network_id: NetworkId,
+}
+
 impl ReputationNet {
-    pub async fn new(message_sender: Sender<Message>) -> Self {
-        let storage = Storage::new().await;
+    pub async fn new(
+        message_sender: Sender<Message>,
+        new_key: NewKey,
+        settings: NetworkSettings,
+    ) -> Self {
+        let NetworkSettings {
+            rpc_limits,
+            discovery,
+            network_id,
+        } = settings;
+        let storage = Storage::new(new_key).await;
         let keypair = storage.own_key().key.clone();
+        let local_peer_id = PeerId::from_public_key(&keypair.public());
         let storage = Arc::new(RwLock::new(storage));
+        let mdns = match discovery.mdns {
+            true => Some(Mdns::new(MdnsConfig::default()).await.unwrap()),
+            false => None,
+        };
+        let mut kademlia_config = KademliaConfig::default();
+        kademlia_config.set_protocol_name(network_id.protocol(KADEMLIA_VERSION).into_bytes());
         let mut repnet = Self {
             gossipsub: Gossipsub::new(
                 MessageAuthenticity::Signed(keypair.clone()),
-                GossipsubConfig::default(),
+                GossipsubConfigBuilder::default()
+                    .protocol_id_prefix(network_id.gossipsub_prefix())
+                    .validate_messages()
+                    .build()
+                    .expect("valid gossipsub config"),
             )
             .unwrap(),
-            mdns: Mdns::new(MdnsConfig::default()).await.unwrap(),
+            mdns: Toggle::from(mdns),
+            kademlia: Kademlia::with_config(
+                local_peer_id,
+                MemoryStore::new(local_peer_id),
+                kademlia_config,
+            ),
+            identify: Identify::new(IdentifyConfig::new(
+                network_id.protocol("1.0"),
+                keypair.public(),
+            )),
             ping: Ping::new(
                 PingConfig::new()
                     .with_interval(Duration::new(90, 0))
                     .with_keep_alive(true),
             ),
             rpc: RequestResponse::new(
-                RpcCodec {},
-                vec![(RpcProtocol::Version1, ProtocolSupport::Full)].into_iter(),
+                RpcCodec {
+                    limits: rpc_limits.clone(),
+                },
+                RpcProtocol::supported(&network_id)
+                    .into_iter()
+                    .map(|protocol| (protocol, ProtocolSupport::Full)),
                 RequestResponseConfig::default(),
             ),
             storage: storage.clone(),
             event_sender: message_sender,
             local_key: keypair.clone(),
             sync_state: SyncState::new(storage).await,
+            penalties: PeerPenalties::default(),
+            rpc_limits,
+            rpc_versions: HashMap::new(),
+            unidentified: HashSet::new(),
+            network_id,
+            bootstrap_peers: HashSet::new(),
+            dial_candidates: vec![],
+            maintenance: MaintenanceSettings::default(),
+            unicode: false,
+            output: OutputFormat::default(),
         };
+        let (params, thresholds) = peer_score_params();
+        repnet
+            .gossipsub
+            .with_peer_score(params, thresholds)
+            .expect("valid peer score parameters");
         for t in repnet.topics().await {
-            repnet
-                .gossipsub
-                .subscribe(&IdentTopic::new(t))
-                .expect("subscribe works");
+            repnet.subscribe(&t);
         }
+        repnet.bootstrap(&discovery.bootstrap);
         repnet
     }
 
+    /// Subscribe to a gossipsub topic, with the score parameters for template topics
+    fn subscribe(&mut self, name: &str) {
+        let topic = self.as_topic(name);
+        if let Err(e) = self.gossipsub.subscribe(&topic) {
+            error!("could not subscribe to {}: {:?}", name, e);
+        }
+        if let Err(e) = self.gossipsub.set_topic_params(topic, topic_score_params()) {
+            error!("could not set score parameters for {}: {}", name, e);
+        }
+    }
+
     pub fn local_peer_id(&self) -> PeerId {
         PeerId::from_public_key(&self.local_key.public())
     }
 
     fn as_topic(&self, s: &str) -> IdentTopic {
-        IdentTopic::new(s)
+        IdentTopic::new(self.network_id.topic(s))
     }
 
     pub async fn topics(&self) -> Vec<String> {
@@ -145,9 +281,11 @@ impl ReputationNet {
         topics
     }
 
+    /// Sign a statement with our own key, using default values for the opinion unless given by the user
     pub async fn sign_statement(
         &mut self,
         statement: PersistResult<Statement>,
+        parameters: &[OpinionParameter],
     ) -> Option<SignedStatement> {
         let opinion = UnsignedOpinion {
             date: Date::today(),
@@ -155,39 +293,53 @@ impl ReputationNet {
             serial: 0,
             certainty: 3,
             comment: "".into(),
-        };
-        let mut storage = self.storage.write().await;
-        let own_key = storage.own_key();
-        let signed_opinion = opinion.sign_using(&statement.data.signable_bytes(), &own_key.key);
-        let signed_opinion = storage
-            .persist_opinion(signed_opinion, &statement.id)
+        }
+        .with_parameters(parameters);
+        let signed_opinion = self
+            .storage
+            .write()
             .await
-            .unwrap();
-        Some(SignedStatement {
-            statement: statement.data,
-            opinions: vec![signed_opinion.data],
-        })
+            .sign_own_opinion(&statement.id, &statement.data, opinion)
+            .await;
+        match signed_opinion {
+            Ok(signed_opinion) => Some(SignedStatement::new(
+                statement.data,
+                vec![signed_opinion.data],
+            )),
+            Err(e) => {
+                error!("could not sign {}: {:?}", statement.data, e);
+                None
+            }
+        }
     }
 
     /// Post a message to a specific peer
-    fn post_message(&mut self, peer: &PeerId, request: RpcRequest) {
-        self.rpc.send_request(peer, request);
+    fn post_message(&mut self, peer: &PeerId, request: RpcRequest) -> RequestId {
+        self.rpc.send_request(peer, request)
     }
 
-    /// Publish a message to a topic for all subscribed peers to see
+    /// Publish a message to a topic for all subscribed peers to see.
+    /// Gossip has no version negotiation, so messages are published in the version 1 encoding
+    /// which all nodes understand.
     fn publish_message(&mut self, topic: IdentTopic, message: BroadcastMessage) {
-        let json = serde_json::to_string(&message).expect("could serialize message");
-        match self.gossipsub.publish(topic, json) {
+        let data = RpcVersion::Version1
+            .encode(&message)
+            .expect("could serialize message");
+        match self.gossipsub.publish(topic, data) {
             Ok(mid) => info!("published as {:?}", mid),
             Err(err) => info!("could not publish: {:?}", err),
         };
     }
 
+    /// Publish a statement with the opinions version 1 nodes can verify: own opinions are re-signed
+    /// in the V1 format, foreign opinions in later formats are left out.
     pub fn publish_statement(&mut self, signed_statement: SignedStatement) {
-        self.publish_message(
-            self.as_topic(&signed_statement.statement.name),
-            BroadcastMessage::Statement(signed_statement),
-        )
+        for signed_statement in version1_statements(vec![signed_statement], &self.local_key) {
+            self.publish_message(
+                self.as_topic(&signed_statement.statement.name),
+                BroadcastMessage::Statement(signed_statement),
+            )
+        }
     }
 
     pub async fn announce_infos(&mut self, date: Date) {
@@ -205,7 +357,18 @@ impl ReputationNet {
                 message,
                 peer_id,
                 topic,
-            } => self.handle_broadcast_message(message, peer_id, topic).await,
+                message_id,
+                propagation_source,
+            } => {
+                self.handle_broadcast_message(
+                    message,
+                    peer_id,
+                    topic,
+                    message_id,
+                    propagation_source,
+                )
+                .await
+            }
             Message::Request {
                 request,
                 peer_id,
@@ -214,8 +377,23 @@ impl ReputationNet {
                 self.handle_request_message(request, peer_id, response_channel)
                     .await
             }
-            Message::Response { peer_id, response } => {
-                self.handle_response_message(response, peer_id).await
+            Message::Response {
+                peer_id,
+                request_id,
+                response,
+            } => {
+                self.handle_response_message(response, peer_id, request_id)
+                    .await
+            }
+            Message::PeerSeen { peer_id, addresses } => {
+                self.remember_peer(peer_id, addresses).await
+            }
+            Message::IdentifyTimeout { peer_id } => {
+                // version 1 nodes have no identify behaviour
+                if self.unidentified.remove(&peer_id) {
+                    info!("no identify info from {}, assuming RPC version 1", peer_id);
+                    self.peer_identified(peer_id, RpcVersion::Version1);
+                }
             }
         }
     }
@@ -225,70 +403,60 @@ impl ReputationNet {
         message: BroadcastMessage,
         peer_id: PeerId,
         _topic: TopicHash,
+        message_id: MessageId,
+        propagation_source: PeerId,
     ) {
-        match message {
+        let acceptance = match message {
             BroadcastMessage::Statement(signed_statement) => {
-                let statement = signed_statement.statement;
-                let mut storage = self.storage.write().await;
-                match storage.persist(statement).await {
-                    Ok(persist_result) => {
-                        info!(
-                            "{} statement {} has id {}",
-                            persist_result.wording(),
-                            persist_result.data,
-                            persist_result.id
-                        );
-                        for signed_opinion in signed_statement.opinions {
-                            let result = storage
-                                .persist_opinion(signed_opinion, &persist_result.id)
-                                .await
-                                .expect("could insert opinion");
-                            info!(
-                                "{} opinion {} has id {}",
-                                result.wording(),
-                                result.data,
-                                result.id
-                            );
-                        }
-                        if persist_result.is_new() && persist_result.name == "template" {
-                            if let Entity::Template(template) = &persist_result.entities[0] {
-                                self.gossipsub
-                                    .subscribe(&self.as_topic(&template.name))
-                                    .unwrap();
-                            };
-                        }
-                        self.sync_state.flush_own_infos()
-                    }
-                    Err(e) => error!("No matching template: {:?}", e),
-                }
+                self.accept_statement(signed_statement, &peer_id).await
             }
             BroadcastMessage::Announcement(infos) => {
-                let requested_updates = self.sync_state.add_infos(&peer_id, &infos).await;
-                for t_name in requested_updates {
-                    self.post_message(
-                        &peer_id,
-                        RpcRequest::OpinionRequest {
-                            name: t_name,
-                            date: infos.date,
-                        },
-                    )
-                }
+                self.accept_announcement(infos, &peer_id).await
             }
+        };
+        // only now gossipsub may forward the message to other peers
+        if let Err(e) = self.gossipsub.report_message_validation_result(
+            &message_id,
+            &propagation_source,
+            acceptance,
+        ) {
+            error!("could not report validation result: {:?}", e);
         }
     }
 
     pub async fn handle_request_message(
         &mut self,
         request: RpcRequest,
-        _peer_id: PeerId,
+        peer_id: PeerId,
         response_channel: ResponseChannel<RpcResponse>,
     ) {
         // println!("got request message {:?} from {}", request, peer_id);
         let response = match request {
+            RpcRequest::Reconcile(step) => {
+                RpcResponse::Reconcile(self.reconciliation_step(&peer_id, step).await)
+            }
+            RpcRequest::SyncInfos(infos) => match self.sync_state.get_own_infos(infos.date).await {
+                Some(own_infos) => RpcResponse::SyncInfos(own_infos),
+                None => RpcResponse::None,
+            },
+            // only version 1 peers send the requests of version 1
             RpcRequest::OpinionRequest { name, date } => {
                 let storage = self.storage.read().await;
                 match storage.list_statements_named_signed(&name, date).await {
-                    Ok(list) => RpcResponse::Statements(list),
+                    Ok(list) => {
+                        let (list, truncated) =
+                            fit_version1(version1_statements(list, &self.local_key));
+                        if truncated {
+                            warn!(
+                                "sent only {} statements named {} on {} to version 1 peer {}",
+                                list.len(),
+                                name,
+                                date,
+                                peer_id
+                            );
+                        }
+                        RpcResponse::Statements(list)
+                    }
                     Err(e) => {
                         error!("{:?}", e);
                         RpcResponse::None
@@ -296,57 +464,152 @@ impl ReputationNet {
                 }
             }
             RpcRequest::TemplateRequest => {
-                let entities = self
-                    .storage
-                    .read()
+                let templates = self
+                    .signed_templates(Id::new(0), usize::MAX)
                     .await
-                    .list_all_templates()
-                    .await
-                    .unwrap();
-                let key = self.storage.read().await.own_key().key.clone();
-                let mut statements = vec![];
-                for entity in entities {
-                    let statement = Statement {
-                        name: "template".into(),
-                        entities: vec![entity],
-                    };
-                    let opinion = UnsignedOpinion::default();
-                    let signed_statement = SignedStatement {
-                        opinions: vec![opinion.sign_using(&statement.signable_bytes(), &key)],
-                        statement: statement,
-                    };
-                    statements.push(signed_statement);
+                    .into_iter()
+                    .map(|(_, statement)| statement)
+                    .collect();
+                let (templates, truncated) =
+                    fit_version1(version1_statements(templates, &self.local_key));
+                if truncated {
+                    warn!(
+                        "sent only {} templates to version 1 peer {}",
+                        templates.len(),
+                        peer_id
+                    );
+                }
+                RpcResponse::Statements(templates)
+            }
+            RpcRequest::TemplatePage { after, limit } => {
+                let limit = limit.clamp(1, self.rpc_limits.page_size);
+                let mut templates = self.signed_templates(Id::new(after), limit + 1).await;
+                let more = templates.len() > limit;
+                templates.truncate(limit);
+                // the next page continues after the last template of this one
+                let next = match templates.last() {
+                    Some((id, _)) if more => Some(Box::new(RpcRequest::TemplatePage {
+                        after: (*id).into(),
+                        limit,
+                    })),
+                    _ => None,
+                };
+                RpcResponse::Page {
+                    statements: templates
+                        .into_iter()
+                        .map(|(_, statement)| statement)
+                        .collect(),
+                    next,
                 }
-                RpcResponse::Statements(statements)
             }
         };
         // println!("sending response {:?}", response);
-        self.rpc.send_response(response_channel, response).unwrap();
+        if self.rpc.send_response(response_channel, response).is_err() {
+            error!("could not send response, connection closed");
+        }
+    }
+
+    /// Our templates after a given statement id, signed for sending them to a peer
+    async fn signed_templates(
+        &mut self,
+        after: Id<Statement>,
+        limit: usize,
+    ) -> Vec<(Id<Statement>, SignedStatement)> {
+        let entities = match self
+            .storage
+            .read()
+            .await
+            .list_templates_after(after, limit)
+            .await
+        {
+            Ok(entities) => entities,
+            Err(e) => {
+                error!("could not list templates: {:?}", e);
+                vec![]
+            }
+        };
+        let key = self.storage.read().await.own_key().key.clone();
+        let mut statements = vec![];
+        for (id, entity) in entities {
+            let statement = Statement {
+                name: "template".into(),
+                entities: vec![entity],
+            };
+            let opinion = UnsignedOpinion::default();
+            let opinions = vec![opinion.sign_using(&statement, &key)];
+            let signed_statement = SignedStatement::new(statement, opinions);
+            statements.push((id, signed_statement));
+        }
+        statements
     }
 
-    pub async fn handle_response_message(&mut self, response: RpcResponse, _peer_id: PeerId) {
+    pub async fn handle_response_message(
+        &mut self,
+        response: RpcResponse,
+        peer_id: PeerId,
+        request_id: RequestId,
+    ) {
         // println!("got response message {:?} from {}", response, peer_id);
+        // ask for the next page right away, the request takes over the role of the answered one
+        let response = match response {
+            RpcResponse::Page {
+                statements,
+                next: Some(next),
+            } => {
+                let next_id = self.post_message(&peer_id, *next);
+                self.sync_state.continued(&request_id, next_id);
+                RpcResponse::Page {
+                    statements,
+                    next: None,
+                }
+            }
+            response => response,
+        };
+        let catch_up = self.sync_state.answered(&request_id);
         match response {
-            RpcResponse::Statements(list) => {
+            RpcResponse::Statements(list) | RpcResponse::Page {
+                statements: list,
+                next: _,
+            } => {
                 for signed_statement in list {
-                    println!("got statement in response: {}", signed_statement.statement);
-                    let mut storage = self.storage.write().await;
-                    let statement_id = storage
-                        .persist(signed_statement.statement)
-                        .await
-                        .expect("could persist statement")
-                        .id;
-                    for opinion in signed_statement.opinions {
-                        storage
-                            .persist_opinion(opinion, &statement_id)
-                            .await
-                            .expect("could persist opinion");
+                    debug!("got statement in response: {}", signed_statement.statement);
+                    self.accept_statement(signed_statement, &peer_id).await;
+                }
+            }
+            RpcResponse::Reconcile(step) => {
+                let answer = self.reconciliation_step(&peer_id, step).await;
+                // the response ends the reconciliation unless something is left to do
+                if !(answer.ranges.is_empty()
+                    && answer.want.is_empty()
+                    && answer.statements.is_empty()
+                    && answer.more.is_empty())
+                {
+                    let request_id = self.post_message(&peer_id, RpcRequest::Reconcile(answer));
+                    if let Some((peer, date)) = catch_up {
+                        self.sync_state.sent(request_id, peer, date);
+                    }
+                }
+            }
+            RpcResponse::SyncInfos(infos) => {
+                let differing = self.sync_state.add_infos(&peer_id, &infos).await;
+                for t_name in differing {
+                    let request_id = self
+                        .start_reconciliation(&peer_id, t_name, infos.date)
+                        .await;
+                    if let (Some(request_id), Some((peer, date))) = (request_id, catch_up) {
+                        self.sync_state.sent(request_id, peer, date);
                     }
                 }
-                self.sync_state.flush_own_infos()
             }
             RpcResponse::None => (),
         }
+        if let Some((peer, date)) = catch_up {
+            if self.sync_state.check_done(&peer, date) {
+                if let Err(e) = self.storage.read().await.peer_synced(&peer).await {
+                    error!("could not record sync with {}: {:?}", peer, e);
+                }
+            }
+        }
     }
 
     pub fn handle_behaviour_event(&mut self, event: OutEvent) {
@@ -356,52 +619,59 @@ impl ReputationNet {
                 info!("ping event: {:?}", event);
             }
             OutEvent::Mdns(event) => self.handle_mdns_event(event),
+            OutEvent::Kademlia(event) => self.handle_kademlia_event(event),
+            OutEvent::Identify(event) => self.handle_identify_event(event),
             OutEvent::Gossipsub(event) => self.handle_gossipsub_event(event),
             OutEvent::Rpc(event) => self.handle_rpc_event(event),
         }
     }
 
-    fn handle_mdns_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(list) => {
-                let mut peers = HashSet::new();
-                for (peer, address) in list {
-                    self.rpc.add_address(&peer, address);
-                    peers.insert(peer);
-                }
-                for peer in peers {
-                    self.gossipsub.add_explicit_peer(&peer);
-                }
-            }
-            MdnsEvent::Expired(list) => {
-                for (peer, _addr) in list {
-                    if !self.mdns.has_node(&peer) {
-                        self.gossipsub.remove_explicit_peer(&peer);
-                    }
-                }
-            }
-        }
-    }
-
     fn handle_gossipsub_event(&mut self, event: GossipsubEvent) {
         match event {
             GossipsubEvent::Message {
-                propagation_source: _,
-                message_id: _,
+                propagation_source,
+                message_id,
                 message,
             } => {
-                // only handle messages coming from some peer
+                // only handle messages coming from some peer, messages are validated before they are forwarded
                 if let Some(peer) = message.source {
-                    let string = String::from_utf8_lossy(&message.data);
+                    let broadcast_message = match RpcVersion::detect(&message.data)
+                        .decode(&message.data, self.rpc_limits.response_size)
+                    {
+                        Ok(broadcast_message) => broadcast_message,
+                        Err(e) => {
+                            warn!("could not decode message from {}: {}", peer, e);
+                            let _ = self.gossipsub.report_message_validation_result(
+                                &message_id,
+                                &propagation_source,
+                                MessageAcceptance::Reject,
+                            );
+                            self.penalize(&peer, Penalty::Unparseable);
+                            return;
+                        }
+                    };
                     let message = Message::Broadcast {
-                        message: serde_json::from_str(&string).expect("network message"),
+                        message: broadcast_message,
                         peer_id: peer,
                         topic: message.topic,
+                        message_id: message_id.clone(),
+                        propagation_source,
                     };
-                    match self.event_sender.try_send(message) {
-                        Err(e) => error!("could not send event: {:?}", e),
-                        _ => (),
+                    if let Err(e) = self.event_sender.try_send(message) {
+                        error!("could not send event: {:?}", e);
+                        // the message will never be validated, don't keep it waiting
+                        let _ = self.gossipsub.report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            MessageAcceptance::Ignore,
+                        );
                     }
+                } else {
+                    let _ = self.gossipsub.report_message_validation_result(
+                        &message_id,
+                        &propagation_source,
+                        MessageAcceptance::Ignore,
+                    );
                 }
             }
             GossipsubEvent::Subscribed {
@@ -437,12 +707,13 @@ impl ReputationNet {
                     }
                 }
                 RequestResponseMessage::Response {
-                    request_id: _,
+                    request_id,
                     response,
                 } => {
                     let response = Message::Response {
                         response: response,
                         peer_id: peer,
+                        request_id,
                     };
                     match self.event_sender.try_send(response) {
                         Err(e) => error!("could not send event: {:?}", e),
@@ -455,7 +726,8 @@ impl ReputationNet {
                 request_id,
                 error,
             } => {
-                println!("outbound failure: {} {} ({})", peer, request_id, error)
+                println!("outbound failure: {} {} ({})", peer, request_id, error);
+                self.sync_state.answered(&request_id);
             }
             RequestResponseEvent::InboundFailure {
                 peer,
@@ -473,12 +745,58 @@ impl ReputationNet {
         }
     }
 
-    pub fn handle_connection_established(&mut self, peer_id: PeerId, num_established: u32) {
+    pub fn handle_connection_established(
+        &mut self,
+        peer_id: PeerId,
+        endpoint: &ConnectedPoint,
+        num_established: u32,
+    ) {
         println!(
             "got connection with peer {:?} ({} connections)",
             peer_id, num_established
         );
-        self.post_message(&peer_id, RpcRequest::TemplateRequest)
+        // only an address we dialed is known to accept connections, the listen addresses follow via identify
+        let addresses = match endpoint {
+            ConnectedPoint::Dialer { address } => vec![address.clone()],
+            ConnectedPoint::Listener { .. } => vec![],
+        };
+        // the templates are requested once identify tells us which RPC versions the peer supports,
+        // peers which do not tell us within IDENTIFY_TIMEOUT are taken for version 1 nodes
+        if !self.rpc_versions.contains_key(&peer_id) && self.unidentified.insert(peer_id) {
+            let mut sender = self.event_sender.clone();
+            spawn(async move {
+                sleep(IDENTIFY_TIMEOUT).await;
+                if let Err(e) = sender.try_send(Message::IdentifyTimeout { peer_id }) {
+                    error!("could not send event: {:?}", e);
+                }
+            });
+        }
+        self.peer_seen(peer_id, addresses);
+    }
+
+    /// The RPC version negotiated with a peer, version 1 until identify tells otherwise
+    fn rpc_version(&self, peer_id: &PeerId) -> RpcVersion {
+        self.rpc_versions
+            .get(peer_id)
+            .copied()
+            .unwrap_or(RpcVersion::Version1)
+    }
+
+    /// Record the RPC version of a newly identified peer and ask for its templates, which starts the catch-up
+    fn peer_identified(&mut self, peer_id: PeerId, version: RpcVersion) {
+        self.unidentified.remove(&peer_id);
+        if self.rpc_versions.insert(peer_id, version).is_some() {
+            return;
+        }
+        let request = match version {
+            RpcVersion::Version1 => RpcRequest::TemplateRequest,
+            RpcVersion::Version2 => RpcRequest::TemplatePage {
+                after: 0,
+                limit: self.rpc_limits.page_size,
+            },
+        };
+        let request_id = self.post_message(&peer_id, request);
+        self.sync_state.add_peer(peer_id, request_id);
     }
 
     pub fn handle_connection_closed(&mut self, peer_id: PeerId, num_established: u32) {
@@ -486,5 +804,11 @@ impl ReputationNet {
             "connection with peer {:?} was closed ({} connections)",
             peer_id, num_established
         );
+        if num_established == 0 {
+            self.sync_state.remove_peer(&peer_id);
+            self.rpc_versions.remove(&peer_id);
+            self.unidentified.remove(&peer_id);
+            self.peer_seen(peer_id, vec![]);
+        }
     }
 }
@@ -0,0 +1,73 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+/// Identifier of a reputation network. It is part of all protocol names and gossipsub topics,
+/// so nodes of different networks do not exchange data even if they discover each other.
+/// The default network has no identifier and keeps the names used before networks were introduced.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NetworkId(Option<String>);
+
+impl NetworkId {
+    /// Name of a protocol in this network, e.g. /reputation-net/<network id>/1.0 for "1.0"
+    pub fn protocol(&self, suffix: &str) -> String {
+        match &self.0 {
+            Some(id) => format!("/reputation-net/{}/{}", id, suffix),
+            None => format!("/reputation-net/{}", suffix),
+        }
+    }
+
+    /// Name of a gossipsub topic in this network
+    pub fn topic(&self, name: &str) -> String {
+        match &self.0 {
+            Some(id) => format!("{}/{}", id, name),
+            None => name.into(),
+        }
+    }
+
+    /// Prefix of the gossipsub protocol, the default one is shared with other libp2p applications
+    pub fn gossipsub_prefix(&self) -> String {
+        match &self.0 {
+            Some(id) => format!("reputation-net/{}/meshsub", id),
+            None => "meshsub".into(),
+        }
+    }
+}
+
+impl Display for NetworkId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match &self.0 {
+            Some(id) => write!(f, "{}", id),
+            None => write!(f, "(default)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidNetworkId;
+
+impl Display for InvalidNetworkId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "network id may only contain letters, digits, '.', '_' and '-'")
+    }
+}
+
+impl std::error::Error for InvalidNetworkId {}
+
+impl FromStr for NetworkId {
+    type Err = InvalidNetworkId;
+    fn from_str(s: &str) -> Result<Self, InvalidNetworkId> {
+        if s.is_empty() {
+            return Ok(Self(None));
+        }
+        if s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+        {
+            Ok(Self(Some(s.into())))
+        } else {
+            Err(InvalidNetworkId)
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    gossipsub::{PeerScoreParams, PeerScoreThresholds, TopicScoreParams},
+    PeerId,
+};
+use log::warn;
+
+use super::ReputationNet;
+
+// Peer scoring: gossipsub computes a score for each peer from its behaviour in the topic meshes,
+// we add application specific penalties for invalid content. Peers with a too low score are banned for a while.
+
+/// peers whose gossipsub score drops below this are disconnected and banned
+const BAN_SCORE: f64 = -50.0;
+/// how long a peer stays banned
+const BAN_DURATION: Duration = Duration::from_secs(3600);
+/// application penalties are halved after this time
+const PENALTY_HALF_LIFE: Duration = Duration::from_secs(600);
+
+/// Misbehaviour of a peer, detected by the application
+#[derive(Clone, Copy, Debug)]
+pub enum Penalty {
+    Unparseable,       // a message which could not be decoded
+    InvalidSignature,  // an opinion whose signature does not verify
+    NoTemplate,        // a statement without a matching template
+    StaleAnnouncement, // an announcement for a date outside the catch-up window
+}
+
+/// Application specific penalties and bans of peers
+#[derive(Default)]
+pub struct PeerPenalties {
+    penalties: HashMap<PeerId, (f64, Instant)>, // accumulated penalty and time of the last update
+    banned: HashMap<PeerId, Instant>,          // banned peers and the end of their ban
+    to_ban: Vec<PeerId>,
+    to_unban: Vec<PeerId>,
+}
+
+impl Penalty {
+    fn weight(&self) -> f64 {
+        match self {
+            Self::Unparseable => 20.0,
+            Self::InvalidSignature => 10.0,
+            Self::NoTemplate => 5.0,
+            Self::StaleAnnouncement => 5.0,
+        }
+    }
+}
+
+impl PeerPenalties {
+    /// add a penalty for a peer and return its total application penalty
+    fn add(&mut self, peer_id: &PeerId, penalty: Penalty) -> f64 {
+        let now = Instant::now();
+        let (value, updated) = self.penalties.entry(*peer_id).or_insert((0.0, now));
+        let half_lives = now.duration_since(*updated).as_secs_f64() / PENALTY_HALF_LIFE.as_secs_f64();
+        *value = *value * 0.5f64.powf(half_lives) + penalty.weight();
+        *updated = now;
+        *value
+    }
+
+    fn ban(&mut self, peer_id: &PeerId) {
+        if !self.banned.contains_key(peer_id) {
+            self.banned.insert(*peer_id, Instant::now() + BAN_DURATION);
+            self.penalties.remove(peer_id);
+            self.to_ban.push(*peer_id);
+        }
+    }
+
+    /// Peers to be banned and peers whose ban has expired since the last call
+    pub fn take_ban_changes(&mut self) -> (Vec<PeerId>, Vec<PeerId>) {
+        let now = Instant::now();
+        let expired = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(peer_id, _)| *peer_id)
+            .collect::<Vec<_>>();
+        for peer_id in expired {
+            self.banned.remove(&peer_id);
+            self.to_unban.push(peer_id);
+        }
+        (
+            std::mem::take(&mut self.to_ban),
+            std::mem::take(&mut self.to_unban),
+        )
+    }
+}
+
+/// Global peer score parameters, topic parameters are added when subscribing
+pub fn peer_score_params() -> (PeerScoreParams, PeerScoreThresholds) {
+    let params = PeerScoreParams {
+        app_specific_weight: 1.0,
+        ..PeerScoreParams::default()
+    };
+    (params, PeerScoreThresholds::default())
+}
+
+/// Score parameters for a template topic. Statements are rare, so missing mesh deliveries are not penalized,
+/// but every invalid message counts.
+pub fn topic_score_params() -> TopicScoreParams {
+    TopicScoreParams {
+        topic_weight: 1.0,
+        time_in_mesh_weight: 0.01,
+        time_in_mesh_quantum: Duration::from_secs(1),
+        time_in_mesh_cap: 3600.0,
+        first_message_deliveries_weight: 1.0,
+        first_message_deliveries_decay: 0.99,
+        first_message_deliveries_cap: 50.0,
+        mesh_message_deliveries_weight: 0.0,
+        mesh_failure_penalty_weight: 0.0,
+        invalid_message_deliveries_weight: -10.0,
+        invalid_message_deliveries_decay: 0.99,
+        ..TopicScoreParams::default()
+    }
+}
+
+impl ReputationNet {
+    /// Record misbehaviour of a peer. Its gossipsub score is reduced accordingly,
+    /// and if it drops too far the peer is banned.
+    pub fn penalize(&mut self, peer_id: &PeerId, penalty: Penalty) {
+        let total = self.penalties.add(peer_id, penalty);
+        warn!("penalty {:?} for peer {}, total {}", penalty, peer_id, total);
+        self.gossipsub.set_application_score(peer_id, -total);
+        let score = self.gossipsub.peer_score(peer_id).unwrap_or(-total);
+        if score < BAN_SCORE {
+            warn!("banning peer {} with score {}", peer_id, score);
+            self.gossipsub.blacklist_peer(peer_id);
+            self.penalties.ban(peer_id);
+        }
+    }
+
+    /// Peers to be banned and unbanned by the swarm
+    pub fn take_ban_changes(&mut self) -> (Vec<PeerId>, Vec<PeerId>) {
+        let (ban, unban) = self.penalties.take_ban_changes();
+        for peer_id in &unban {
+            self.gossipsub.remove_blacklisted_peer(peer_id);
+        }
+        (ban, unban)
+    }
+}
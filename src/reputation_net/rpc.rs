@@ -1,24 +1,203 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
 use async_std::io::{Result};
 use async_trait::async_trait;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
 
 use libp2p::request_response::*;
 use libp2p::core::upgrade::{read_length_prefixed,write_length_prefixed};
+use libp2p::identity::Keypair;
+
+use crate::model::{SignatureFormat, SignedStatement};
+
+use super::{messages::*, NetworkId};
+
+/// version 2 messages larger than this are compressed
+const COMPRESSION_THRESHOLD: usize = 1024;
+/// first byte of a version 2 message: how the rest is encoded
+const UNCOMPRESSED: u8 = 0;
+const DEFLATE: u8 = 1;
 
-use super::messages::*;
+/// version 1 nodes reject responses larger than this
+pub const VERSION1_RESPONSE_SIZE: usize = 20000;
 
+/// Size limits for RPC messages
 #[derive(Debug, Clone)]
-pub struct RpcCodec {}
+pub struct RpcLimits {
+    pub request_size: usize,  // maximum size of a request in bytes, reconciliation steps are requests, too
+    pub response_size: usize, // maximum size of a response in bytes
+    pub page_size: usize,     // maximum number of opinions in a response, larger results are paginated
+}
+
+impl Default for RpcLimits {
+    fn default() -> Self {
+        Self {
+            request_size: 16 << 20,
+            response_size: 16 << 20,
+            page_size: 1000,
+        }
+    }
+}
 
-/// The RPC protocol
+#[derive(Debug, Clone)]
+pub struct RpcCodec {
+    pub limits: RpcLimits,
+}
+
+/// The RPC protocol versions, the newest one supported by both peers is negotiated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RpcVersion {
+    Version1, // JSON
+    Version2, // CBOR, optionally compressed with deflate
+}
+
+/// An RPC protocol version as named in a network, e.g. /reputation-net/<network id>/2.0
 #[derive(Clone)]
-pub enum RpcProtocol {
-    Version1,
+pub struct RpcProtocol {
+    pub version: RpcVersion,
+    name: Vec<u8>,
 }
 
-impl ProtocolName for RpcProtocol{
+impl ProtocolName for RpcProtocol {
     fn protocol_name(&self) -> &[u8] {
-        b"/reputation-net/1.0"
+        &self.name
+    }
+}
+
+impl RpcProtocol {
+    /// All supported versions in a network, in order of preference
+    pub fn supported(network_id: &NetworkId) -> Vec<Self> {
+        vec![RpcVersion::Version2, RpcVersion::Version1]
+            .into_iter()
+            .map(|version| Self {
+                name: network_id.protocol(version.number()).into_bytes(),
+                version,
+            })
+            .collect()
+    }
+
+    /// The version negotiated with a peer supporting the given protocols, as told by identify
+    pub fn negotiated(network_id: &NetworkId, protocols: &[String]) -> Option<RpcVersion> {
+        Self::supported(network_id)
+            .into_iter()
+            .find(|protocol| protocols.iter().any(|p| p.as_bytes() == protocol.name))
+            .map(|protocol| protocol.version)
+    }
+}
+
+/// Statements as sent to version 1 peers, which only understand opinions in the V1 format.
+/// Our own opinions are signed again in that format, other opinions in later formats are left out
+pub fn version1_statements(
+    statements: Vec<SignedStatement>,
+    own_key: &Keypair,
+) -> Vec<SignedStatement> {
+    let own_key_public = own_key.public();
+    statements
+        .into_iter()
+        .filter_map(|signed_statement| {
+            let statement = signed_statement.statement;
+            let opinions = signed_statement
+                .opinions
+                .into_iter()
+                .filter_map(|opinion| match opinion.format {
+                    SignatureFormat::V1 => Some(opinion),
+                    _ if opinion.signer.key == own_key_public => Some(
+                        opinion
+                            .data
+                            .sign_using_format(&statement, own_key, SignatureFormat::V1),
+                    ),
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            (!opinions.is_empty()).then_some(SignedStatement::new(statement, opinions))
+        })
+        .collect()
+}
+
+/// The leading statements which fit into a version 1 response, and whether others were left out.
+/// Version 1 knows no pagination, the peer gets the rest via later requests or gossip
+pub fn fit_version1(mut statements: Vec<SignedStatement>) -> (Vec<SignedStatement>, bool) {
+    let mut size = serde_json::to_vec(&RpcResponse::Statements(vec![]))
+        .map(|data| data.len())
+        .unwrap_or_default();
+    let fitting = statements
+        .iter()
+        .take_while(|statement| {
+            // each statement but the first is preceded by a comma
+            size += serde_json::to_vec(statement)
+                .map(|data| data.len() + 1)
+                .unwrap_or(VERSION1_RESPONSE_SIZE);
+            size <= VERSION1_RESPONSE_SIZE
+        })
+        .count();
+    let truncated = fitting < statements.len();
+    statements.truncate(fitting);
+    (statements, truncated)
+}
+
+impl RpcVersion {
+    fn number(&self) -> &'static str {
+        match self {
+            Self::Version1 => "1.0",
+            Self::Version2 => "2.0",
+        }
+    }
+
+    /// The version of an encoded message: version 2 messages start with their encoding byte,
+    /// version 1 messages are JSON text
+    pub fn detect(data: &[u8]) -> Self {
+        match data.first() {
+            Some(&UNCOMPRESSED) | Some(&DEFLATE) => Self::Version2,
+            _ => Self::Version1,
+        }
+    }
+
+    /// Encode a message for this protocol version
+    pub fn encode<M: Serialize>(&self, message: &M) -> Result<Vec<u8>> {
+        match self {
+            Self::Version1 => Ok(serde_json::to_vec(message)?),
+            Self::Version2 => {
+                let data = serde_cbor::to_vec(message).map_err(invalid_data)?;
+                if data.len() <= COMPRESSION_THRESHOLD {
+                    let mut result = vec![UNCOMPRESSED];
+                    result.extend(data);
+                    return Ok(result);
+                }
+                let mut encoder = DeflateEncoder::new(vec![DEFLATE], Compression::default());
+                encoder.write_all(&data)?;
+                encoder.finish()
+            }
+        }
     }
+
+    /// Decode a message for this protocol version, which must not expand to more than `limit` bytes
+    pub fn decode<M: DeserializeOwned>(&self, data: &[u8], limit: usize) -> Result<M> {
+        match self {
+            Self::Version1 => Ok(serde_json::from_slice(data)?),
+            Self::Version2 => match data.split_first() {
+                Some((&UNCOMPRESSED, data)) => serde_cbor::from_slice(data).map_err(invalid_data),
+                Some((&DEFLATE, data)) => {
+                    let mut decompressed = vec![];
+                    DeflateDecoder::new(data)
+                        .take(limit as u64 + 1)
+                        .read_to_end(&mut decompressed)?;
+                    if decompressed.len() > limit {
+                        return Err(invalid_data("decompressed message too large"));
+                    }
+                    serde_cbor::from_slice(&decompressed).map_err(invalid_data)
+                }
+                _ => Err(invalid_data("unknown message encoding")),
+            },
+        }
+    }
+}
+
+fn invalid_data<E>(error: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::new(ErrorKind::InvalidData, error)
 }
 
 #[async_trait]
@@ -29,53 +208,241 @@ impl RequestResponseCodec for RpcCodec {
 
     async fn read_request<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> Result<Self::Request>
     where
         T: futures::AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, 1000).await?;
-        let request = serde_json::from_slice(&data)?;
-        Ok(request)
+        let data = read_length_prefixed(io, self.limits.request_size).await?;
+        protocol.version.decode(&data, self.limits.request_size)
     }
 
     async fn read_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
     ) -> Result<Self::Response>
     where
         T: futures::AsyncRead + Unpin + Send,
     {
-        let data = read_length_prefixed(io, 20000).await?;
-        let response = serde_json::from_slice(&data)?;
-        Ok(response)
+        let data = read_length_prefixed(io, self.limits.response_size).await?;
+        protocol.version.decode(&data, self.limits.response_size)
     }
 
     async fn write_request<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         req: Self::Request,
     ) -> Result<()>
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let json_data = serde_json::to_vec(&req).unwrap();
-        write_length_prefixed(io, &json_data).await
+        let data = protocol.version.encode(&req)?;
+        write_length_prefixed(io, &data).await
     }
 
     async fn write_response<T>(
         &mut self,
-        _protocol: &Self::Protocol,
+        protocol: &Self::Protocol,
         io: &mut T,
         res: Self::Response,
     ) -> Result<()>
     where
         T: futures::AsyncWrite + Unpin + Send,
     {
-        let json_data = serde_json::to_vec(&res).unwrap();
-        write_length_prefixed(io, &json_data).await
+        let data = protocol.version.encode(&res)?;
+        write_length_prefixed(io, &data).await
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use serde::Deserialize;
+
+    use super::*;
+    use crate::model::{tests::example_keypair, Date, Opinion, Statement, UnsignedOpinion};
+    use crate::storage::SyncInfos;
+
+    // the messages of version 1 as nodes before version 2 know them
+    #[derive(Deserialize)]
+    enum BaselineRequest {
+        TemplateRequest,
+        OpinionRequest { name: String, date: Date },
+    }
+
+    #[derive(Deserialize)]
+    enum BaselineResponse {
+        None,
+        Statements(Vec<BaselineSignedStatement>),
+    }
+
+    #[derive(Deserialize)]
+    enum BaselineBroadcast {
+        Announcement(SyncInfos),
+        Statement(BaselineSignedStatement),
+    }
+
+    #[derive(Deserialize)]
+    struct BaselineSignedStatement {
+        statement: Statement,
+        opinions: Vec<String>,
     }
-}
\ No newline at end of file
+
+    // such nodes split opinions into date;valid;serial;certainty;comment;signer;signature
+    fn baseline_opinion(s: &str) -> Opinion {
+        let parts = s.split(';').collect::<Vec<_>>();
+        assert_eq!(parts.len(), 7, "{}", s);
+        assert!(parts[0].parse::<u32>().is_ok(), "{}", s);
+        Opinion::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn version1_compatibility() {
+        let version = RpcVersion::Version1;
+        let limit = RpcLimits::default().response_size;
+        let request = version
+            .encode(&RpcRequest::OpinionRequest {
+                name: "abuse".into(),
+                date: Date::from(18924),
+            })
+            .unwrap();
+        match version.decode(&request, limit).unwrap() {
+            BaselineRequest::OpinionRequest { name, date } => {
+                assert_eq!(name, "abuse");
+                assert_eq!(date, Date::from(18924));
+            }
+            _ => panic!("expected an opinion request"),
+        }
+        let request = version.encode(&RpcRequest::TemplateRequest).unwrap();
+        assert!(matches!(
+            version.decode(&request, limit).unwrap(),
+            BaselineRequest::TemplateRequest
+        ));
+
+        let own_key = example_keypair();
+        let other_key = Keypair::generate_ed25519();
+        let statement = Statement::from_str("abuse(example.com)").unwrap();
+        let opinion = UnsignedOpinion::default();
+        let signed_statement = SignedStatement::new(
+            statement.clone(),
+            vec![
+                opinion.clone().sign_using(&statement, &own_key),
+                opinion.clone().sign_using(&statement, &other_key),
+                opinion.sign_using_format(&statement, &other_key, SignatureFormat::V1),
+            ],
+        );
+        let other_statement = Statement::from_str("abuse(example.net)").unwrap();
+        let opinions = vec![UnsignedOpinion::default().sign_using(&other_statement, &other_key)];
+        let foreign_v2 = SignedStatement::new(other_statement, opinions);
+        let statements = version1_statements(vec![signed_statement, foreign_v2], &own_key);
+        let response = version
+            .encode(&RpcResponse::Statements(statements))
+            .unwrap();
+        let statements = match version.decode(&response, limit).unwrap() {
+            BaselineResponse::Statements(statements) => statements,
+            BaselineResponse::None => panic!("expected statements"),
+        };
+        // the statement with only a foreign opinion in version 2 format is left out
+        assert_eq!(statements.len(), 1);
+        assert_eq!(statements[0].statement, statement);
+        let opinions = statements[0]
+            .opinions
+            .iter()
+            .map(|s| baseline_opinion(s))
+            .collect::<Vec<_>>();
+        assert_eq!(opinions.len(), 2);
+        assert!(opinions.iter().any(|o| o.signer.key == own_key.public()));
+        assert!(opinions.iter().all(|o| o.verify_signature(&statement)));
+    }
+
+    #[test]
+    fn version1_gossip() {
+        let version = RpcVersion::Version1;
+        let limit = RpcLimits::default().response_size;
+        let own_key = example_keypair();
+        let other_key = Keypair::generate_ed25519();
+        let statement = Statement::from_str("abuse(example.com)").unwrap();
+        let signed_statement = SignedStatement::new(
+            statement.clone(),
+            vec![
+                UnsignedOpinion::default().sign_using(&statement, &own_key),
+                UnsignedOpinion::default().sign_using(&statement, &other_key),
+            ],
+        );
+        // as published by ReputationNet::publish_statement
+        let published = version1_statements(vec![signed_statement], &own_key);
+        assert_eq!(published.len(), 1);
+        let message = version
+            .encode(&BroadcastMessage::Statement(published[0].clone()))
+            .unwrap();
+        let signed_statement = match version.decode(&message, limit).unwrap() {
+            BaselineBroadcast::Statement(signed_statement) => signed_statement,
+            BaselineBroadcast::Announcement(_) => panic!("expected a statement"),
+        };
+        assert_eq!(signed_statement.statement, statement);
+        assert_eq!(signed_statement.opinions.len(), 1);
+        let opinion = baseline_opinion(&signed_statement.opinions[0]);
+        assert_eq!(opinion.signer.key, own_key.public());
+        assert!(opinion.verify_signature(&statement));
+
+        let message = version
+            .encode(&BroadcastMessage::Announcement(SyncInfos {
+                date: Date::from(18924),
+                infos: Default::default(),
+            }))
+            .unwrap();
+        match version.decode(&message, limit).unwrap() {
+            BaselineBroadcast::Announcement(infos) => assert_eq!(infos.date, Date::from(18924)),
+            BaselineBroadcast::Statement(_) => panic!("expected an announcement"),
+        }
+    }
+
+    #[test]
+    fn version1_size() {
+        let key = example_keypair();
+        let statements = (0..1000)
+            .map(|i| {
+                let statement =
+                    Statement::from_str(&format!("abuse(host{}.example.com)", i)).unwrap();
+                let opinion = UnsignedOpinion::default().sign_using_format(
+                    &statement,
+                    &key,
+                    SignatureFormat::V1,
+                );
+                SignedStatement::new(statement, vec![opinion])
+            })
+            .collect::<Vec<_>>();
+        let (fitting, truncated) = fit_version1(statements);
+        assert!(truncated);
+        assert!(!fitting.is_empty());
+        let response = RpcVersion::Version1
+            .encode(&RpcResponse::Statements(fitting))
+            .unwrap();
+        assert!(response.len() <= VERSION1_RESPONSE_SIZE);
+
+        let (fitting, truncated) = fit_version1(vec![]);
+        assert!(fitting.is_empty() && !truncated);
+    }
+
+    #[test]
+    fn negotiated_version() {
+        let network_id = NetworkId::default();
+        let old = vec!["/ipfs/id/1.0.0".to_string(), "/reputation-net/1.0".into()];
+        let new = vec![
+            "/reputation-net/2.0".to_string(),
+            "/reputation-net/1.0".into(),
+        ];
+        assert_eq!(
+            RpcProtocol::negotiated(&network_id, &old),
+            Some(RpcVersion::Version1)
+        );
+        assert_eq!(
+            RpcProtocol::negotiated(&network_id, &new),
+            Some(RpcVersion::Version2)
+        );
+        assert_eq!(RpcProtocol::negotiated(&network_id, &old[..1]), None);
+    }
+}
@@ -1,28 +1,199 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::Instant,
+};
 
 use async_std::sync::RwLock;
-use libp2p::PeerId;
-use log::info;
+use itertools::Itertools;
+use libp2p::{request_response::RequestId, PeerId};
+use log::{error, info};
 
 use crate::{
     model::Date,
-    storage::{Storage, SyncInfos},
+    storage::{initial_ranges, reconcile, Storage, SyncInfos},
 };
 
+use super::{Reconcile, ReputationNet, RpcRequest, RpcVersion};
+
 // Synchronization support (basically allowing nodes to fill their database on startup)
 
+/// Settings for the automatic catch-up with connected peers
+#[derive(Clone, Debug)]
+pub struct SyncSettings {
+    pub days: u32,          // minimum number of days up to today which are compared with peers
+    pub concurrency: usize, // maximum number of catch-up requests in flight
+}
+
+impl Default for SyncSettings {
+    fn default() -> Self {
+        Self {
+            days: 30,
+            concurrency: 4,
+        }
+    }
+}
+
+/// Progress of the catch-up with one peer
+#[derive(Debug, Default)]
+pub struct PeerProgress {
+    pending: VecDeque<Date>,    // dates still to be compared in the current round
+    pub synced: BTreeSet<Date>, // dates compared and reconciled in the current round
+    pub rounds: usize,          // number of completed rounds
+    pub last_round: Option<Instant>, // completion time of the last round
+}
+
+impl PeerProgress {
+    /// Number of dates still to be compared in the current round
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
 /// A node's own guess about its synchronization state
 pub struct SyncState {
     own_infos: HashMap<Date, SyncInfos>,
     storage: Arc<RwLock<Storage>>,
+    settings: SyncSettings,
+    active_days: u32, // days up to today with opinions still valid in our storage
+    peers: HashMap<PeerId, PeerProgress>,
+    in_flight: HashMap<RequestId, (PeerId, Date)>, // catch-up requests waiting for a response
+    template_requests: HashMap<RequestId, PeerId>, // catch-up starts when the peer's templates are known
 }
 
 impl SyncState {
     pub async fn new(storage: Arc<RwLock<Storage>>) -> Self {
-        Self {
+        let mut sync_state = Self {
             own_infos: HashMap::new(),
             storage,
+            settings: SyncSettings::default(),
+            active_days: 0,
+            peers: HashMap::new(),
+            in_flight: HashMap::new(),
+            template_requests: HashMap::new(),
+        };
+        sync_state.update_window().await;
+        sync_state
+    }
+
+    /// Extend the catch-up window to the oldest date with opinions which are still valid
+    pub async fn update_window(&mut self) {
+        match self.storage.read().await.active_days().await {
+            Ok(days) => self.active_days = days,
+            Err(e) => error!("could not determine the dates with valid opinions: {:?}", e),
+        }
+    }
+
+    pub fn set_settings(&mut self, settings: SyncSettings) {
+        self.settings = settings;
+    }
+
+    /// A peer was connected and asked for its templates, the catch-up starts with their arrival
+    pub fn add_peer(&mut self, peer: PeerId, template_request: RequestId) {
+        self.peers.entry(peer).or_default();
+        self.template_requests.insert(template_request, peer);
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerId) {
+        self.peers.remove(peer);
+    }
+
+    /// Start a new catch-up round with a peer, newest dates first.
+    /// All dates with opinions still valid are compared, but at least the configured number of days
+    fn schedule(&mut self, peer: &PeerId) {
+        let today = Date::today().d;
+        let days = self.settings.days.max(self.active_days);
+        if let Some(progress) = self.peers.get_mut(peer) {
+            progress.synced.clear();
+            progress.pending = (0..days)
+                .filter_map(|i| today.checked_sub(i).map(Date::from))
+                .collect();
+        }
+    }
+
+    /// true if peers may announce sync infos for a date: it lies within the catch-up window,
+    /// with a day of tolerance for peers whose day has already begun
+    pub fn in_window(&self, date: Date) -> bool {
+        let today = Date::today().d;
+        let days = self.settings.days.max(self.active_days);
+        date.d <= today + 1 && date.d + days > today
+    }
+
+    /// Start a new catch-up round with all peers which are not busy with one
+    pub fn schedule_all(&mut self) {
+        let idle = self
+            .peers
+            .iter()
+            .filter(|(peer, progress)| progress.pending.is_empty() && !self.is_busy(peer))
+            .map(|(peer, _)| *peer)
+            .collect::<Vec<_>>();
+        for peer in idle {
+            self.schedule(&peer);
+        }
+    }
+
+    fn is_busy(&self, peer: &PeerId) -> bool {
+        self.in_flight.values().any(|(p, _)| p == peer)
+    }
+
+    /// The next date to compare with a peer, if the concurrency limit allows another request
+    pub fn next_catch_up(&mut self) -> Option<(PeerId, Date)> {
+        if self.in_flight.len() >= self.settings.concurrency {
+            return None;
+        }
+        self.peers
+            .iter_mut()
+            .find_map(|(peer, progress)| progress.pending.pop_front().map(|date| (*peer, date)))
+    }
+
+    /// Record a catch-up request sent to a peer
+    pub fn sent(&mut self, request_id: RequestId, peer: PeerId, date: Date) {
+        self.in_flight.insert(request_id, (peer, date));
+    }
+
+    /// The request for the next page of a response continues the answered request
+    pub fn continued(&mut self, request_id: &RequestId, next_id: RequestId) {
+        if let Some(peer) = self.template_requests.remove(request_id) {
+            self.template_requests.insert(next_id, peer);
+        }
+        if let Some(entry) = self.in_flight.remove(request_id) {
+            self.in_flight.insert(next_id, entry);
+        }
+    }
+
+    /// A response (or failure) for a request arrived. Return the peer and date if it was a catch-up request
+    pub fn answered(&mut self, request_id: &RequestId) -> Option<(PeerId, Date)> {
+        if let Some(peer) = self.template_requests.remove(request_id) {
+            self.schedule(&peer);
+        }
+        self.in_flight.remove(request_id)
+    }
+
+    /// Mark a date as synchronized with a peer unless there are further requests for it.
+    /// Returns true if this completed a catch-up round with the peer
+    pub fn check_done(&mut self, peer: &PeerId, date: Date) -> bool {
+        if self.in_flight.values().any(|entry| *entry == (*peer, date)) {
+            return false;
+        }
+        let busy = self.is_busy(peer);
+        if let Some(progress) = self.peers.get_mut(peer) {
+            progress.synced.insert(date);
+            if progress.pending.is_empty() && !busy {
+                progress.rounds += 1;
+                progress.last_round = Some(Instant::now());
+                info!(
+                    "caught up with peer {} for {} days",
+                    peer,
+                    progress.synced.len()
+                );
+                return true;
+            }
         }
+        false
+    }
+
+    pub fn progress(&self) -> impl Iterator<Item = (&PeerId, &PeerProgress)> {
+        self.peers.iter()
     }
 
     /// Update our own infos for a given date. Return infos if successful
@@ -37,22 +208,35 @@ impl SyncState {
         }
     }
 
-    /// return the template names for which we want data from this peer
+    /// return the template names for which our data differs from this peer's.
+    /// Names of templates we don't know are ignored, there is nothing we could reconcile for them
     pub async fn add_infos(&mut self, peer: &PeerId, infos: &SyncInfos) -> Vec<String> {
         info!("adding info {:?} for peer {:?}", infos, peer);
         let mut template_names = vec![];
         if let Some(own_infos) = self.get_own_infos(infos.date).await {
+            let known = self
+                .storage
+                .read()
+                .await
+                .templates()
+                .into_iter()
+                .map(|(_, template)| template.name)
+                .collect::<HashSet<_>>();
             for (key, info) in infos.infos.iter() {
-                if let Some(own_info) = own_infos.infos.get(key) {
-                    if own_info.suggests_update(&info) {
-                        template_names.push(key.clone());
-                    }
-                } else {
+                if known.contains(key) && own_infos.infos.get(key) != Some(info) {
+                    template_names.push(key.clone());
+                }
+            }
+            for key in own_infos.infos.keys() {
+                if !infos.infos.contains_key(key) {
                     template_names.push(key.clone());
                 }
             }
         }
-        info!("need updates for template names {:?}", template_names);
+        info!(
+            "need reconciliation for template names {:?}",
+            template_names
+        );
         template_names
     }
 
@@ -67,3 +251,144 @@ impl SyncState {
         self.own_infos = HashMap::new()
     }
 }
+
+impl ReputationNet {
+    /// Start reconciling the opinions of a given date on statements with a given name with a peer.
+    /// Version 1 peers do not know reconciliation and are asked for all their opinions instead
+    pub async fn start_reconciliation(
+        &mut self,
+        peer: &PeerId,
+        name: String,
+        date: Date,
+    ) -> Option<RequestId> {
+        if self.rpc_version(peer) == RpcVersion::Version1 {
+            return Some(self.post_message(peer, RpcRequest::OpinionRequest { name, date }));
+        }
+        let signatures = match self.storage.read().await.list_signatures(&name, date).await {
+            Ok(signatures) => signatures,
+            Err(e) => {
+                error!(
+                    "could not list signatures for {} on {}: {:?}",
+                    name, date, e
+                );
+                return None;
+            }
+        };
+        let request = Reconcile {
+            name,
+            date,
+            ranges: initial_ranges(&signatures),
+            want: vec![],
+            statements: vec![],
+            more: vec![],
+        };
+        Some(self.post_message(peer, RpcRequest::Reconcile(request)))
+    }
+
+    /// Send as many catch-up requests as the concurrency limit allows
+    pub async fn drive_catch_up(&mut self) {
+        while let Some((peer, date)) = self.sync_state.next_catch_up() {
+            // version 1 peers know no sync infos requests, they are asked for the opinions of each template
+            if self.rpc_version(&peer) == RpcVersion::Version1 {
+                let names = self
+                    .storage
+                    .read()
+                    .await
+                    .templates()
+                    .into_iter()
+                    .map(|(_, template)| template.name)
+                    .unique()
+                    .collect::<Vec<_>>();
+                for name in names {
+                    let request_id =
+                        self.post_message(&peer, RpcRequest::OpinionRequest { name, date });
+                    self.sync_state.sent(request_id, peer, date);
+                }
+                continue;
+            }
+            let infos = match self.sync_state.get_own_infos(date).await {
+                Some(infos) => infos,
+                None => SyncInfos {
+                    date,
+                    infos: HashMap::new(),
+                },
+            };
+            let request_id = self.post_message(&peer, RpcRequest::SyncInfos(infos));
+            self.sync_state.sent(request_id, peer, date);
+        }
+    }
+
+    pub fn set_sync_settings(&mut self, settings: SyncSettings) {
+        self.sync_state.set_settings(settings);
+    }
+
+    /// Start a new catch-up round with all connected peers
+    pub async fn schedule_catch_up(&mut self) {
+        self.sync_state.update_window().await;
+        self.sync_state.schedule_all();
+    }
+
+    /// Print the catch-up progress with each connected peer
+    pub fn print_catch_up_progress(&self) {
+        for (peer, progress) in self.sync_state.progress() {
+            println!(
+                "{}: {} days synced, {} pending, {} rounds completed{}",
+                peer,
+                progress.synced.len(),
+                progress.pending(),
+                progress.rounds,
+                match progress.last_round {
+                    Some(instant) => format!(", last {}s ago", instant.elapsed().as_secs()),
+                    None => "".into(),
+                }
+            );
+        }
+    }
+
+    /// Handle a reconciliation step from a peer: accept the opinions it sent, and compute the answer
+    /// with the ranges which still differ, the opinions it wants or lacks, and the opinions we want.
+    /// At most a page of opinions is sent in one step, the peer asks for the others in the next steps.
+    pub async fn reconciliation_step(&mut self, peer: &PeerId, step: Reconcile) -> Reconcile {
+        let Reconcile {
+            name,
+            date,
+            ranges,
+            mut want,
+            statements,
+            more,
+        } = step;
+        for signed_statement in statements {
+            self.accept_statement(signed_statement, peer).await;
+        }
+        let storage = self.storage.read().await;
+        let signatures = match storage.list_signatures(&name, date).await {
+            Ok(signatures) => signatures,
+            Err(e) => {
+                error!(
+                    "could not list signatures for {} on {}: {:?}",
+                    name, date, e
+                );
+                vec![]
+            }
+        };
+        let mut result = reconcile(&signatures, &ranges);
+        result.want.extend(more);
+        want.extend(result.send);
+        let more = want.split_off(want.len().min(self.rpc_limits.page_size));
+        let statements = match storage.list_statements_with_signatures(&want).await {
+            Ok(statements) => statements,
+            Err(e) => {
+                error!("could not list opinions for {} on {}: {:?}", name, date, e);
+                vec![]
+            }
+        };
+        Reconcile {
+            name,
+            date,
+            ranges: result.ranges,
+            want: result.want,
+            statements,
+            more,
+        }
+    }
+}
@@ -0,0 +1,101 @@
+use std::{io, iter, net::IpAddr, path::Path, time::Duration};
+
+use async_std::net::ToSocketAddrs;
+use libp2p::{
+    core::{
+        either::EitherTransport,
+        muxing::StreamMuxerBox,
+        transport::{Boxed, OptionalTransport},
+        upgrade::{SelectUpgrade, Version},
+    },
+    dns::DnsConfig,
+    identity::Keypair,
+    mplex::MplexConfig,
+    multiaddr::Protocol,
+    noise::{Keypair as NoiseKeypair, NoiseConfig, X25519Spec},
+    pnet::{PnetConfig, PreSharedKey},
+    tcp::TcpConfig,
+    websocket::WsConfig,
+    yamux::YamuxConfig,
+    Multiaddr, PeerId, Transport,
+};
+
+/// Settings for connections to other nodes
+#[derive(Clone, Debug, Default)]
+pub struct TransportSettings {
+    pub psk: Option<PreSharedKey>, // pre-shared key of a private network
+    pub websocket: bool,           // also accept and dial /ws addresses
+}
+
+/// Read a pre-shared key in the usual swarm.key format (/key/swarm/psk/1.0.0/, /base16/, hex key)
+pub fn read_swarm_key(file: impl AsRef<Path>) -> io::Result<PreSharedKey> {
+    let text = std::fs::read_to_string(file)?;
+    text.parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Build the transport: TCP and optionally WebSocket with DNS resolution, noise encryption and yamux or mplex.
+/// With a pre-shared key, only nodes knowing the same key can connect (libp2p private network).
+pub async fn build_transport(
+    keypair: &Keypair,
+    settings: TransportSettings,
+) -> io::Result<Boxed<(PeerId, StreamMuxerBox)>> {
+    let dns_tcp = DnsConfig::system(TcpConfig::new().nodelay(true)).await?;
+    let ws_dns_tcp = match settings.websocket {
+        true => OptionalTransport::some(WsConfig::new(dns_tcp.clone())),
+        false => OptionalTransport::none(),
+    };
+    // the DNS transport accepts every address when dialing, so WebSocket has to come first
+    let transport = ws_dns_tcp.or_transport(dns_tcp);
+    let transport = match settings.psk {
+        Some(psk) => EitherTransport::Left(
+            transport.and_then(move |socket, _| PnetConfig::new(psk).handshake(socket)),
+        ),
+        None => EitherTransport::Right(transport),
+    };
+
+    let noise_keys = NoiseKeypair::<X25519Spec>::new()
+        .into_authentic(keypair)
+        .expect("could sign noise keypair");
+
+    Ok(transport
+        .upgrade(Version::V1)
+        .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(SelectUpgrade::new(
+            YamuxConfig::default(),
+            MplexConfig::default(),
+        ))
+        .timeout(Duration::from_secs(20))
+        .boxed())
+}
+
+/// Listening needs IP addresses, so a leading /dns, /dns4 or /dns6 host name is resolved first
+pub async fn resolve_listen_address(address: &Multiaddr) -> io::Result<Vec<Multiaddr>> {
+    let mut protocols = address.iter();
+    let (host, ipv4, ipv6) = match protocols.next() {
+        Some(Protocol::Dns(host)) => (host, true, true),
+        Some(Protocol::Dns4(host)) => (host, true, false),
+        Some(Protocol::Dns6(host)) => (host, false, true),
+        _ => return Ok(vec![address.clone()]),
+    };
+    let rest = protocols.collect::<Vec<_>>();
+    let mut result = vec![];
+    for socket_address in (host.as_ref(), 0).to_socket_addrs().await? {
+        let ip = match socket_address.ip() {
+            IpAddr::V4(ip) if ipv4 => Protocol::Ip4(ip),
+            IpAddr::V6(ip) if ipv6 => Protocol::Ip6(ip),
+            _ => continue,
+        };
+        let resolved = iter::once(ip).chain(rest.iter().cloned()).collect::<Multiaddr>();
+        if !result.contains(&resolved) {
+            result.push(resolved);
+        }
+    }
+    if result.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("could not resolve {}", address),
+        ));
+    }
+    Ok(result)
+}
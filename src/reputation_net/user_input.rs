@@ -2,9 +2,16 @@ use std::{error::Error, str::FromStr, time::Instant};
 
 use log::{error, info};
 
-use crate::model::{Date, Statement};
+use nom::combinator::all_consuming;
+use serde_json::json;
 
-/// functions handling user input (currently simple stdin, will be changed to handle readline and proper output)
+use crate::{
+    console::{Table, COMMANDS},
+    model::{parser, Date, PublicSuffixList, Statement},
+    storage::{Get, Id},
+};
+
+/// functions handling user input from the console. Listings are shown as a table or as JSON, see `output`
 use super::{Entity, ReputationNet};
 
 impl ReputationNet {
@@ -20,14 +27,15 @@ impl ReputationNet {
             }
             return;
         }
-        match what.parse::<Statement>() {
-            Ok(statement) => {
+        // a statement, optionally followed by opinion parameters like certainty=-2 valid=7d comment="..."
+        match all_consuming(parser::statement_input)(what) {
+            Ok((_, (statement, parameters))) => {
                 let template = statement.specific_template();
                 let result = self
                     .storage
                     .write()
                     .await
-                    .persist_statement_hashing_emails(statement)
+                    .persist_statement_hashing_emails(statement.clone())
                     .await;
                 match result {
                     Ok(actual_statement) => {
@@ -37,21 +45,28 @@ impl ReputationNet {
                             actual_statement.data,
                             actual_statement.id
                         );
-                        let signed_statement = self.sign_statement(actual_statement).await.unwrap();
-                        self.publish_statement(signed_statement);
+                        if let Some(signed_statement) =
+                            self.sign_statement(actual_statement, &parameters).await
+                        {
+                            self.publish_statement(signed_statement);
+                        }
                     }
                     Err(_e) => {
                         error!("No matching template: {}", template);
                         error!("Available:");
-                        for t in self
+                        if let Ok(templates) = self
                             .storage
                             .read()
                             .await
                             .list_templates(&template.name)
                             .await
-                            .iter()
                         {
-                            error!("  {:?}", t)
+                            for t in templates {
+                                match statement.matches_template(&t) {
+                                    Err(mismatch) => error!("  {} ({})", t, mismatch),
+                                    Ok(()) => error!("  {}", t),
+                                }
+                            }
                         }
                     }
                 }
@@ -71,6 +86,116 @@ impl ReputationNet {
                     error!("error: {:?}", e);
                 }
             }
+            "normalize" => match self.storage.write().await.normalize_statements().await {
+                Ok(changed) => info!("normalized {} statements", changed),
+                Err(e) => error!("error: {:?}", e),
+            },
+            "verify" => {
+                let quarantine = words.get(1) == Some(&"quarantine");
+                match self.storage.write().await.verify_opinions(quarantine).await {
+                    Ok(broken) => {
+                        for (statement, opinion) in &broken {
+                            println!("invalid signature on {}: {}", statement, opinion);
+                        }
+                        info!(
+                            "{} opinions with invalid signatures{}",
+                            broken.len(),
+                            if quarantine { " quarantined" } else { "" }
+                        );
+                    }
+                    Err(e) => error!("error: {:?}", e),
+                }
+            }
+            "reload-psl" => match words.get(1) {
+                Some(file) => {
+                    if let Err(e) = PublicSuffixList::load(file) {
+                        error!("could not load public suffix list {}: {}", file, e);
+                    }
+                }
+                None => error!("usage: !reload-psl <file>"),
+            },
+            "help" => {
+                let mut table = Table::new(&["command", "description"]);
+                for (command, description) in COMMANDS {
+                    table.push(vec![json!(format!("!{}", command)), json!(description)]);
+                }
+                println!("{}", table.render(self.output));
+            }
+            "templates" => {
+                let mut table = Table::new(&["id", "name", "template"]);
+                for (id, template) in self.storage.read().await.templates() {
+                    table.push(vec![
+                        json!(i64::from(id)),
+                        json!(template.name),
+                        json!(template.to_string()),
+                    ]);
+                }
+                println!("{}", table.render(self.output));
+            }
+            "signers" => {
+                let mut table = Table::new(&["id", "signer", "trust"]);
+                for (id, signer, trust) in self.storage.read().await.signers() {
+                    table.push(vec![json!(i64::from(id)), json!(signer), json!(trust)]);
+                }
+                println!("{}", table.render(self.output));
+            }
+            "trust" => {
+                let mut table = Table::new(&["trust", "signer"]);
+                for (signer, trust) in self.storage.read().await.trusted_signers() {
+                    table.push(vec![json!(trust), json!(signer)]);
+                }
+                println!("{}", table.render(self.output));
+            }
+            "peers" => self.print_known_peers().await,
+            "stats" => {
+                let stats = match self.storage.read().await.stats().await {
+                    Ok(stats) => stats,
+                    Err(e) => return error!("error: {:?}", e),
+                };
+                let mut table = Table::new(&["item", "count"]);
+                table.push(vec![json!("statements"), json!(stats.statements)]);
+                table.push(vec![json!("opinions"), json!(stats.opinions)]);
+                table.push(vec![json!("templates"), json!(stats.templates)]);
+                table.push(vec![json!("signers"), json!(stats.signers)]);
+                table.push(vec![json!("known peers"), json!(stats.peers)]);
+                table.push(vec![
+                    json!("connected peers"),
+                    json!(self.sync_state.progress().count()),
+                ]);
+                println!("{}", table.render(self.output));
+            }
+            "opinions" => match words.get(1).map(|id| id.parse::<i64>()) {
+                Some(Ok(id)) => {
+                    if let Err(e) = self.print_opinions(Id::new(id)).await {
+                        error!("error: {:?}", e);
+                    }
+                }
+                _ => error!("usage: !opinions <statement id>"),
+            },
+            "retract" => match words[1..].join(" ").parse::<Statement>() {
+                Ok(statement) => {
+                    let retraction = self
+                        .storage
+                        .write()
+                        .await
+                        .retract_statement(statement.clone())
+                        .await;
+                    match retraction {
+                        Ok(Some(signed_statement)) => {
+                            println!("retracted {}", signed_statement.statement);
+                            self.sync_state.flush_own_infos();
+                            self.publish_statement(signed_statement);
+                        }
+                        Ok(None) => error!("no opinion of ours to retract on {}", statement),
+                        Err(e) => error!("error: {:?}", e),
+                    }
+                }
+                Err(e) => error!("usage: !retract <statement> ({})", e),
+            },
+            "catch-up" => {
+                self.schedule_catch_up().await;
+                self.print_catch_up_progress();
+            }
             "sync" => {
                 let date = if words.len() > 1 {
                     match Date::from_str(words[1]) {
@@ -93,6 +218,45 @@ impl ReputationNet {
         }
     }
 
+    async fn print_opinions(&mut self, id: Id<Statement>) -> Result<(), Box<dyn Error>> {
+        let storage = self.storage.read().await;
+        if Get::<Statement>::get(&*storage, id).await?.is_none() {
+            error!("no statement with id {}", id);
+            return Ok(());
+        }
+        let mut table = Table::new(&[
+            "id",
+            "date",
+            "until",
+            "serial",
+            "certainty",
+            "signer",
+            "comment",
+        ]);
+        for opinion in storage.list_opinions_on(id).await? {
+            let data = &opinion.data.data;
+            table.push(vec![
+                json!(i64::from(opinion.id)),
+                json!(data.date.to_string()),
+                json!(data.last_date().to_string()),
+                json!(data.serial),
+                json!(data.certainty),
+                json!(opinion.data.signer),
+                json!(data.comment),
+            ]);
+        }
+        println!("{}", table.render(self.output));
+        Ok(())
+    }
+
+    fn display_statement(&self, statement: &Statement) -> String {
+        if self.unicode {
+            statement.to_unicode()
+        } else {
+            statement.to_string()
+        }
+    }
+
     async fn local_query(&mut self, query: &str) -> Result<(), Box<dyn Error>> {
         let entity = Entity::from_str(query)?;
         let instant = Instant::now();
@@ -100,15 +264,20 @@ impl ReputationNet {
             .storage
             .read()
             .await
-            .find_statements_about(&entity)
+            .score_statements_about(&entity)
             .await?;
         let duration = instant.elapsed();
         info!("Execution time: {:?}", duration);
         if statements.len() == 0 {
             println!("No matches");
         }
-        for statement in statements {
-            println!("{}: {}", statement.id, statement.data);
+        for (statement, score) in statements {
+            println!(
+                "{}: {} (score {})",
+                statement.id,
+                self.display_statement(&statement.data),
+                score
+            );
             let opinions = self
                 .storage
                 .read()
@@ -0,0 +1,122 @@
+use libp2p::{gossipsub::MessageAcceptance, PeerId};
+use log::{error, info, warn};
+
+use crate::{
+    model::{Entity, SignatureFormat, SignedStatement},
+    storage::{Repository, SyncInfos},
+};
+
+use super::{peer_score::Penalty, ReputationNet};
+
+// Validation of statements and opinions received from other nodes, via gossipsub or RPC
+
+impl ReputationNet {
+    /// Verify a received signed statement and persist it with all of its validly signed opinions.
+    /// Opinions with invalid signatures are dropped. The result tells gossipsub whether the message
+    /// may be forwarded to other peers: only messages without any invalid part are.
+    /// Only invalid V2 signatures are penalized, V1 signatures may have been made by nodes
+    /// which wrote the statement differently, e.g. without normalizing its domain names.
+    pub async fn accept_statement(
+        &mut self,
+        signed_statement: SignedStatement,
+        peer_id: &PeerId,
+    ) -> MessageAcceptance {
+        // V1 signatures are checked against the statement as received, before it was normalized
+        let (valid, invalid): (Vec<_>, Vec<_>) = signed_statement
+            .opinions
+            .iter()
+            .cloned()
+            .partition(|opinion| signed_statement.verify_opinion(opinion));
+        let reworded = signed_statement.is_reworded();
+        let statement = signed_statement.statement;
+        for opinion in &invalid {
+            warn!(
+                "dropping opinion from {} on {} with invalid signature by {}",
+                peer_id, statement, opinion.signer
+            );
+        }
+        let only_v1 = invalid
+            .iter()
+            .all(|opinion| opinion.format == SignatureFormat::V1);
+        if !only_v1 {
+            self.penalize(peer_id, Penalty::InvalidSignature);
+        }
+        // a reworded statement may come from an honest version 1 node, it is not forwarded nor held against it
+        let invalid_acceptance = if only_v1 && reworded {
+            MessageAcceptance::Ignore
+        } else {
+            MessageAcceptance::Reject
+        };
+        if valid.is_empty() {
+            warn!("dropping statement {} from {} without valid opinions", statement, peer_id);
+            return invalid_acceptance;
+        }
+        if !self.storage.read().await.has_matching_template(&statement) {
+            warn!("dropping statement {} from {} without matching template", statement, peer_id);
+            self.penalize(peer_id, Penalty::NoTemplate);
+            return MessageAcceptance::Reject;
+        }
+        let mut storage = self.storage.write().await;
+        let persist_result = match storage.persist(statement).await {
+            Ok(persist_result) => persist_result,
+            Err(e) => {
+                error!("could not persist statement from {}: {:?}", peer_id, e);
+                return MessageAcceptance::Ignore;
+            }
+        };
+        info!(
+            "{} statement {} has id {}",
+            persist_result.wording(),
+            persist_result.data,
+            persist_result.id
+        );
+        for signed_opinion in valid {
+            match storage
+                .persist_opinion(signed_opinion, &persist_result.id)
+                .await
+            {
+                Ok(result) => info!(
+                    "{} opinion {} has id {}",
+                    result.wording(),
+                    result.data,
+                    result.id
+                ),
+                Err(e) => error!("could not persist opinion from {}: {:?}", peer_id, e),
+            }
+        }
+        drop(storage);
+        if persist_result.is_new() && persist_result.name == "template" {
+            if let Entity::Template(template) = &persist_result.entities[0] {
+                self.subscribe(&template.name);
+            };
+        }
+        self.sync_state.flush_own_infos();
+        if invalid.is_empty() {
+            MessageAcceptance::Accept
+        } else {
+            invalid_acceptance
+        }
+    }
+
+    /// Check a received announcement and reconcile the templates for which our data differs.
+    /// Announcements for dates outside the catch-up window are rejected, like invalid statements.
+    pub async fn accept_announcement(
+        &mut self,
+        infos: SyncInfos,
+        peer_id: &PeerId,
+    ) -> MessageAcceptance {
+        if !self.sync_state.in_window(infos.date) {
+            warn!(
+                "dropping announcement from {} for {} outside the catch-up window",
+                peer_id, infos.date
+            );
+            self.penalize(peer_id, Penalty::StaleAnnouncement);
+            return MessageAcceptance::Reject;
+        }
+        let differing = self.sync_state.add_infos(peer_id, &infos).await;
+        for t_name in differing {
+            self.start_reconciliation(peer_id, t_name, infos.date).await;
+        }
+        MessageAcceptance::Accept
+    }
+}
@@ -1,15 +1,117 @@
-/// The scheduler is responsible for repeating cleanup and health actions as well as for triggering synchronizations
-/// with peers which may be out of sync.
+use std::time::{Duration, Instant};
+
+use async_std::task::sleep;
+use futures::{channel::mpsc::Sender, SinkExt};
+use log::debug;
+
+/// The periodic jobs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Job {
+    Cleanup,    // remove expired opinions, unused statements and stale peers
+    Refresh,    // re-sign own opinions before they expire
+    Announce,   // announce our sync infos for recent dates
+    CatchUp,    // start a new catch-up round with connected peers
+    RandomWalk, // look for more peers in the DHT
+}
+
+/// Intervals of the periodic jobs, a zero interval disables a job
+#[derive(Clone, Debug)]
+pub struct SchedulerSettings {
+    pub cleanup: Duration,
+    pub refresh: Duration,
+    pub announce: Duration,
+    pub catch_up: Duration,
+    pub random_walk: Duration,
+}
+
+impl Default for SchedulerSettings {
+    fn default() -> Self {
+        Self {
+            cleanup: Duration::from_secs(3600),
+            refresh: Duration::from_secs(3600),
+            announce: Duration::from_secs(300),
+            catch_up: Duration::from_secs(600),
+            random_walk: Duration::from_secs(300),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ScheduledJob {
+    job: Job,
+    interval: Duration,
+    due: Instant,
+}
 
+/// The scheduler is responsible for repeating cleanup and health actions as well as for triggering synchronizations
+/// with peers which may be out of sync. It only decides when a job is due, the network loop runs it.
 #[derive(Debug)]
-pub struct Scheduler {}
+pub struct Scheduler {
+    jobs: Vec<ScheduledJob>,
+}
 
 impl Scheduler {
-    pub fn new() -> Self {
-        Self {}
+    /// Create a scheduler for the enabled jobs, each of which is first due after its interval
+    pub fn new(settings: &SchedulerSettings) -> Self {
+        let now = Instant::now();
+        let jobs = [
+            (Job::Cleanup, settings.cleanup),
+            (Job::Refresh, settings.refresh),
+            (Job::Announce, settings.announce),
+            (Job::CatchUp, settings.catch_up),
+            (Job::RandomWalk, settings.random_walk),
+        ]
+        .into_iter()
+        .filter(|(_, interval)| !interval.is_zero())
+        .map(|(job, interval)| ScheduledJob {
+            job,
+            interval,
+            due: now + interval,
+        })
+        .collect();
+        Self { jobs }
+    }
+
+    /// The job which is due next
+    fn next(&mut self) -> Option<&mut ScheduledJob> {
+        self.jobs.iter_mut().min_by_key(|job| job.due)
+    }
+
+    /// Send each job to the network loop when it is due, until the network loop is gone
+    pub async fn run(mut self, mut sender: Sender<Job>) {
+        while let Some(next) = self.next() {
+            sleep(next.due.saturating_duration_since(Instant::now())).await;
+            next.due = Instant::now() + next.interval;
+            debug!("job {:?} is due", next.job);
+            if sender.send(next.job).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn disabled_jobs() {
+        let settings = SchedulerSettings {
+            refresh: Duration::ZERO,
+            random_walk: Duration::ZERO,
+            ..Default::default()
+        };
+        let scheduler = Scheduler::new(&settings);
+        let jobs = scheduler.jobs.iter().map(|j| j.job).collect::<Vec<_>>();
+        assert_eq!(jobs, [Job::Cleanup, Job::Announce, Job::CatchUp]);
     }
 
-    pub async fn run(&self) {
-        
+    #[test]
+    fn next_job() {
+        let mut scheduler = Scheduler::new(&SchedulerSettings::default());
+        let next = scheduler.next().unwrap();
+        assert_eq!(next.job, Job::Announce);
+        next.due += Duration::from_secs(600);
+        assert_eq!(scheduler.next().unwrap().job, Job::RandomWalk);
     }
 }
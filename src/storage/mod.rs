@@ -2,9 +2,7 @@
 use std::{collections::HashMap, str::FromStr};
 
 use itertools::Itertools;
-use libp2p::identity::Keypair;
-
-use log::{debug, info};
+use log::{debug, error, info};
 // library imports
 use sqlx::{
     sqlite::{SqliteConnectOptions, SqlitePool, SqlitePoolOptions, SqliteRow},
@@ -13,13 +11,16 @@ use sqlx::{
 
 // own imports
 use crate::model::{
-    Date, Entity, Opinion, OwnKey, PublicKey, SignedStatement, Statement, Template, UnsignedOpinion,
+    compute_trust, Date, Entity, KeyAlgorithm, NewKey, Opinion, OwnKey, PublicKey, Score,
+    ScoreSettings, SignatureFormat, SignedStatement, Statement, Template, TrustEdge,
+    TrustSettings, UnsignedOpinion,
 };
 
 mod schema;
 pub use schema::*;
 mod repository;
 pub use repository::*;
+mod peer;
 mod statement;
 mod sync_info;
 pub use sync_info::*;
@@ -36,25 +37,57 @@ pub struct Storage {
     templates: HashMap<Id<Statement>, Template>,
     signers: HashMap<Id<Statement>, PublicKey>,
     own_key: OwnKey,
+    score_settings: ScoreSettings,
+    trust_settings: TrustSettings,
+    trust: HashMap<PublicKey, f64>, // effective trust in signers, computed from trusts() statements
+    verify_on_load: bool,           // check signatures of opinions read from the database
+    refresh_horizon: u16,           // own opinions expiring within this many days are renewed
+}
+
+/// Size of the database, for display
+#[derive(Debug)]
+pub struct Stats {
+    pub statements: i64,
+    pub opinions: i64,
+    pub templates: usize,
+    pub signers: usize,
+    pub peers: i64, // known peers, connected or not
 }
 
 impl Storage {
     /// create a new initialized instance of the database.
-    /// existing outdated entities, statements and opinions will be cleaned up
-    pub async fn new() -> Self {
-        let mut options = SqliteConnectOptions::from_str(DATABASE_URL).unwrap();
+    /// existing outdated entities, statements and opinions will be cleaned up.
+    /// If the database does not contain a private key yet, it is created as specified by `new_key`.
+    pub async fn new(new_key: NewKey) -> Self {
+        Self::open(DATABASE_URL, 5, new_key).await
+    }
+
+    /// an empty database which only lives as long as the storage, for tests.
+    /// Connections to an in-memory database share a cache with table-level locks, so only one is used.
+    #[cfg(test)]
+    pub async fn temporary() -> Self {
+        Self::open("sqlite::memory:", 1, NewKey::default()).await
+    }
+
+    async fn open(url: &str, max_connections: u32, new_key: NewKey) -> Self {
+        let mut options = SqliteConnectOptions::from_str(url).unwrap();
         options.log_statements(log::LevelFilter::Debug);
         let mut db = Self {
             pool: SqlitePoolOptions::new()
-                .max_connections(5)
+                .max_connections(max_connections)
                 .connect_with(options)
                 .await
                 .unwrap(),
             templates: HashMap::new(),
             signers: HashMap::new(),
-            own_key: OwnKey::new(),
+            own_key: OwnKey::new(KeyAlgorithm::Secp256k1).expect("could generate key"),
+            score_settings: ScoreSettings::default(),
+            trust_settings: TrustSettings::default(),
+            trust: HashMap::new(),
+            verify_on_load: false,
+            refresh_horizon: 7,
         };
-        db.initialize_database().await.expect("could initialize");
+        db.initialize_database(&new_key).await.expect("could initialize");
         db.cleanup().await.expect("could cleanup");
         db
     }
@@ -62,10 +95,11 @@ impl Storage {
     /// initialize the database with the schema and well-known facts
     /// this should be idempotent, i.e. if the database is already initialized it should do nothing,
     /// but for a partially initialized database it should complete initialization.
-    async fn initialize_database(&mut self) -> Result<(), Error> {
+    async fn initialize_database(&mut self, new_key: &NewKey) -> Result<(), Error> {
         // perform migrations as necessary
         let migration = sqlx::migrate!();
         migration.run(&self.pool).await.expect("could migrate");
+        self.fix_statement_entities(false).await?;
 
         // insert the root template, this is currently manual
         let template_statement = Statement::from_str("template(template(Template))").unwrap();
@@ -75,8 +109,12 @@ impl Storage {
         let signer_statement = Statement::from_str("template(signer(Signer))").unwrap();
         let signer_statement = self.persist(signer_statement).await?;
 
+        // insert the "trusts" template for the web of trust
+        let trusts_statement = Statement::from_str("template(trusts(Signer,Signer))").unwrap();
+        let trusts_statement = self.persist(trusts_statement).await?;
+
         // make sure an owner trust entry exists
-        self.ensure_own_key().await?;
+        self.ensure_own_key(new_key).await?;
 
         // sign the predefined statements with it
         let own_key = self.own_key.clone();
@@ -84,17 +122,22 @@ impl Storage {
             .await?;
         self.sign_statement_default(signer_statement.data, &own_key)
             .await?;
+        self.sign_statement_default(trusts_statement.data, &own_key)
+            .await?;
 
         // fill templates and signers
         self.read_templates().await?;
         self.read_signers().await?;
+        self.update_trust().await?;
 
         Ok(())
     }
 
     pub async fn read_templates(&mut self) -> Result<(), Error> {
         let template_entries = sqlx::query_as::<DB, (Id<Statement>, String)>(
-            "select id, entity_1 from statement where name='template'",
+            "select s.id, e.entity
+            from statement s join statement_entity e on s.id = e.statement_id
+            where s.name = 'template' and e.position = 0",
         )
         .fetch_all(&self.pool)
         .await?;
@@ -108,7 +151,9 @@ impl Storage {
 
     pub async fn read_signers(&mut self) -> Result<(), Error> {
         let signer_entries = sqlx::query_as::<DB, (Id<Statement>, String)>(
-            "select id, entity_1 from statement where name='signer'",
+            "select s.id, e.entity
+            from statement s join statement_entity e on s.id = e.statement_id
+            where s.name = 'signer' and e.position = 0",
         )
         .fetch_all(&self.pool)
         .await?;
@@ -130,7 +175,7 @@ impl Storage {
             }
         }
         for (_id, template) in &self.templates {
-            if statement.matches_template(&template) {
+            if statement.matches_template(template).is_ok() {
                 return true;
             }
         }
@@ -157,28 +202,59 @@ impl Storage {
     pub async fn list_all_templates(&self) -> Result<Vec<Entity>, Error> {
         let rows = sqlx::query_scalar::<DB, String>(
             "select
-                entity_1
+                e.entity
             from
-                statement
+                statement s join statement_entity e on s.id = e.statement_id
             where
-                name = 'template'",
+                s.name = 'template' and e.position = 0
+            order by
+                s.id",
         )
         .fetch_all(&self.pool)
         .await?;
         Ok(rows.iter().map(|s| Entity::from_str(s).unwrap()).collect())
     }
 
-    /// this currently does not handle multi-entity statements
+    /// Up to `limit` templates with statement ids greater than `after`, ordered by statement id
+    pub async fn list_templates_after(
+        &self,
+        after: Id<Statement>,
+        limit: usize,
+    ) -> Result<Vec<(Id<Statement>, Entity)>, Error> {
+        let rows = sqlx::query_as::<DB, (Id<Statement>, String)>(
+            "select
+                s.id, e.entity
+            from
+                statement s join statement_entity e on s.id = e.statement_id
+            where
+                s.name = 'template' and e.position = 0 and s.id > ?
+            order by
+                s.id
+            limit ?",
+        )
+        .bind(after)
+        .bind(limit.min(i64::MAX as usize) as i64)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, s)| (id, Entity::from_str(&s).unwrap()))
+            .collect())
+    }
+
+    /// find statements referencing the entity in any position
     pub async fn find_statements_referencing(
         &self,
         entity: &Entity,
     ) -> Result<Vec<Persistent<Statement>>, Error> {
         let rows = match entity.cidr_minmax() {
             (Some(min), Some(max)) => {
-                sqlx::query_as::<DB, DbStatement>(&format!(
-                    "select {} from {} where cidr_min <= ? and cidr_max >= ?",
-                    DbStatement::COLUMNS,
-                    DbStatement::TABLE
+                sqlx::query_as::<DB, DbStatementWithEntity>(&format!(
+                    "select {} from {} where statement.id in
+                        (select e.statement_id from statement_entity e where e.cidr_min <= ? and e.cidr_max >= ?)
+                    order by statement.id, statement_entity.position",
+                    DbStatementWithEntity::COLUMNS,
+                    DbStatementWithEntity::TABLE
                 ))
                 .bind(min)
                 .bind(max)
@@ -186,21 +262,19 @@ impl Storage {
                 .await?
             }
             _ => {
-                sqlx::query_as::<DB, DbStatement>(&format!(
-                    "select {} from {} where entity_1 = ?",
-                    DbStatement::COLUMNS,
-                    DbStatement::TABLE
+                sqlx::query_as::<DB, DbStatementWithEntity>(&format!(
+                    "select {} from {} where statement.id in
+                        (select e.statement_id from statement_entity e where e.entity = ?)
+                    order by statement.id, statement_entity.position",
+                    DbStatementWithEntity::COLUMNS,
+                    DbStatementWithEntity::TABLE
                 ))
                 .bind(entity.to_string())
                 .fetch_all(&self.pool)
                 .await?
             }
         };
-        let mut statements = vec![];
-        for db_row in rows {
-            statements.push(self.convert(db_row).await?)
-        }
-        Ok(statements)
+        self.convert(rows).await
     }
 
     pub async fn list_opinions_on(
@@ -215,8 +289,12 @@ impl Storage {
         .bind(id)
         .fetch_all(&self.pool)
         .await?;
+        let statement = match self.verify_on_load {
+            true => self.get(id).await?,
+            false => None,
+        };
         let opinions = rows
-            .iter()
+            .into_iter()
             .map(|row| {
                 let signer = self.signers.get(&row.signer_id).unwrap().clone();
                 let opinion = Opinion {
@@ -225,17 +303,35 @@ impl Storage {
                         valid: row.valid,
                         serial: row.serial,
                         certainty: row.certainty,
-                        comment: String::new(),
+                        comment: row.comment.unwrap_or_default(),
                     },
                     signer,
                     signature: base64::decode(&row.signature).unwrap(),
+                    format: SignatureFormat::from_i64(row.format.into())
+                        .expect("known signature format"),
                 };
                 row.id.with(opinion)
             })
+            .filter(|opinion| match &statement {
+                Some(statement) => self.verified(statement, opinion),
+                None => true,
+            })
             .collect();
         Ok(opinions)
     }
 
+    // check the signature of an opinion loaded from the database, logging failures
+    fn verified(&self, statement: &Statement, opinion: &Opinion) -> bool {
+        let ok = opinion.verify_signature(statement);
+        if !ok {
+            error!(
+                "ignoring opinion with invalid signature on {}: {}",
+                statement, opinion
+            );
+        }
+        ok
+    }
+
     pub async fn list_statements_named_signed(
         &self,
         name: &str,
@@ -244,7 +340,8 @@ impl Storage {
         // it would be nicer to use group_by() but that causes problems with async/await, so we use plain old for loops
         let rows: Vec<DbStatementWithOpinion> =
             sqlx::query_as::<DB, DbStatementWithOpinion>(&format!(
-            "select {} from {} where statement.name = ? and opinion.date = ? order by statement.id",
+            "select {} from {} where statement.name = ? and opinion.date = ?
+            order by statement.id, opinion.id, statement_entity.position",
             DbStatementWithOpinion::COLUMNS,
             DbStatementWithOpinion::TABLE
         ))
@@ -252,91 +349,145 @@ impl Storage {
             .bind(date)
             .fetch_all(&self.pool)
             .await?;
+        self.signed_statements_from_rows(rows).await
+    }
+
+    /// The sorted signatures of all opinions of a given date on statements with a given name
+    pub async fn list_signatures(&self, name: &str, date: Date) -> Result<Vec<String>, Error> {
+        sqlx::query_scalar::<DB, String>(
+            "select o.signature
+            from statement s join opinion o on s.id = o.statement_id
+            where s.name = ? and o.date = ?
+            order by o.signature",
+        )
+        .bind(name)
+        .bind(date)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    /// The number of days up to today with opinions which are still valid, 0 if there are none
+    pub async fn active_days(&self) -> Result<u32, Error> {
+        let today = Date::today();
+        let oldest = sqlx::query_scalar::<DB, Option<Date>>(
+            "select min(date) from opinion where date + valid >= ?",
+        )
+        .bind(today)
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(match oldest {
+            Some(oldest) if oldest.d <= today.d => today.d - oldest.d + 1,
+            _ => 0,
+        })
+    }
+
+    /// The opinions with the given signatures, together with their statements
+    pub async fn list_statements_with_signatures(
+        &self,
+        signatures: &[String],
+    ) -> Result<Vec<SignedStatement>, Error> {
+        let mut rows: Vec<DbStatementWithOpinion> = vec![];
+        // stay well below the maximum number of parameters of a query
+        for chunk in signatures.chunks(500) {
+            let query = format!(
+                "select {} from {} where opinion.signature in ({})
+                order by statement.id, opinion.id, statement_entity.position",
+                DbStatementWithOpinion::COLUMNS,
+                DbStatementWithOpinion::TABLE,
+                vec!["?"; chunk.len()].join(",")
+            );
+            let mut query = sqlx::query_as::<DB, DbStatementWithOpinion>(&query);
+            for signature in chunk {
+                query = query.bind(signature);
+            }
+            rows.extend(query.fetch_all(&self.pool).await?);
+        }
+        rows.sort_by_key(|row| row.statement.id);
+        self.signed_statements_from_rows(rows).await
+    }
+
+    // group rows ordered by statement id, opinion id and entity position into signed statements
+    async fn signed_statements_from_rows(
+        &self,
+        rows: Vec<DbStatementWithOpinion>,
+    ) -> Result<Vec<SignedStatement>, Error> {
         let mut signed_statements: Vec<SignedStatement> = vec![];
         let mut last_id = Id::new(0);
+        let mut first_opinion_id = Id::new(0);
+        let mut last_opinion_id = Id::new(0);
         for row in rows {
-            let p_statement: Persistent<Statement> = row.statement.into();
-            let opinion = Opinion::from_using_storage(row.opinion, &self).await;
-            if p_statement.id == last_id {
-                let len = signed_statements.len();
-                let last = &mut signed_statements[len - 1];
-                last.opinions.push(opinion);
-            } else {
-                signed_statements.push(SignedStatement {
-                    statement: p_statement.data,
-                    opinions: vec![opinion],
-                });
-                last_id = p_statement.id
+            if row.statement.id != last_id {
+                signed_statements.push(SignedStatement::new(
+                    Statement {
+                        name: row.statement.name,
+                        entities: vec![],
+                    },
+                    vec![],
+                ));
+                last_id = row.statement.id;
+                first_opinion_id = row.opinion.id;
+            }
+            let last = signed_statements.last_mut().unwrap();
+            // the entities are repeated for every opinion, take them from the first one
+            if row.opinion.id == first_opinion_id {
+                last.statement
+                    .entities
+                    .push(Entity::from_str(&row.entity).unwrap());
+            }
+            if row.opinion.id != last_opinion_id {
+                last_opinion_id = row.opinion.id;
+                last.opinions
+                    .push(Opinion::from_using_storage(row.opinion, self).await);
             }
         }
-        // println!("signed_statements: {:?}", signed_statements);
+        if self.verify_on_load {
+            for signed_statement in &mut signed_statements {
+                let statement = &signed_statement.statement;
+                signed_statement
+                    .opinions
+                    .retain(|opinion| self.verified(statement, opinion));
+            }
+            signed_statements.retain(|signed_statement| !signed_statement.opinions.is_empty());
+        }
         Ok(signed_statements)
     }
 
     async fn try_select_statement(
         &self,
-        name: &str,
-        entity_1: &str,
-        entity_2: &Option<String>,
-        entity_3: &Option<String>,
-        entity_4: &Option<String>,
+        statement: &Statement,
     ) -> Result<Option<Id<Statement>>, Error> {
-        let mut sql = "select id from statement where name=? and entity_1=?".to_owned();
-        if let Some(_) = entity_2 {
-            sql.push_str(" and entity_2=?");
-            if let Some(_) = entity_3 {
-                sql.push_str(" and entity_3=?");
-                if let Some(_) = entity_4 {
-                    sql.push_str(" and entity_4=?");
-                }
-            }
-        }
-        let mut query = sqlx::query_scalar::<DB, Id<Statement>>(&sql)
-            .bind(name)
-            .bind(entity_1);
-        if let Some(s) = entity_2 {
-            query = query.bind(s);
-            if let Some(s) = entity_3 {
-                query = query.bind(s);
-                if let Some(s) = entity_3 {
-                    query = query.bind(s)
-                }
-            }
-        }
-        match query.fetch_optional(&self.pool).await? {
-            Some(id) => Ok(Some(id)),
-            None => Ok(None),
-        }
+        sqlx::query_scalar::<DB, Id<Statement>>("select id from statement where content = ?")
+            .bind(statement.to_string())
+            .fetch_optional(&self.pool)
+            .await
     }
 
-    async fn try_insert_statement(
-        &self,
-        name: &str,
-        entity_1: &str,
-        entity_2: &Option<String>,
-        entity_3: &Option<String>,
-        entity_4: &Option<String>,
-        cidr_min: &Option<String>,
-        cidr_max: &Option<String>,
-    ) -> Result<Id<Statement>, Error> {
+    async fn try_insert_statement(&self, statement: &Statement) -> Result<Id<Statement>, Error> {
         let mut tx = self.pool.begin().await?;
-        let query = sqlx::query::<DB>(
-            "insert into
-            statement(name, entity_1, entity_2, entity_3, entity_4, cidr_min, cidr_max)
-            values(?,?,?,?,?,?,?)
-            ",
-        )
-        .bind(name)
-        .bind(entity_1)
-        .bind(entity_2)
-        .bind(entity_3)
-        .bind(entity_4)
-        .bind(cidr_min)
-        .bind(cidr_max);
-        query.execute(&mut tx).await?;
+        sqlx::query::<DB>("insert into statement(name, content) values(?,?)")
+            .bind(&statement.name)
+            .bind(statement.to_string())
+            .execute(&mut tx)
+            .await?;
         let id = sqlx::query_scalar::<DB, Id<Statement>>("select last_insert_rowid()")
             .fetch_one(&mut tx)
             .await?;
+        for (position, entity) in statement.entities.iter().enumerate() {
+            let (cidr_min, cidr_max) = entity.cidr_minmax();
+            sqlx::query::<DB>(
+                "insert into
+                statement_entity(statement_id, position, entity_type, entity, cidr_min, cidr_max)
+                values(?,?,?,?,?,?)",
+            )
+            .bind(id)
+            .bind(position as u32)
+            .bind(entity.entity_type() as u8)
+            .bind(entity.to_string())
+            .bind(cidr_min)
+            .bind(cidr_max)
+            .execute(&mut tx)
+            .await?;
+        }
         tx.commit().await?;
         Ok(id)
     }
@@ -368,16 +519,20 @@ impl Storage {
         let signer_result = self.persist(signer).await.unwrap();
         let opinion_data = &opinion.data;
 
-        let prev_opinion_result = sqlx::query_as::<DB, (Id<Opinion>, Date, u8)>(
-            "select id,date,serial from opinion where statement_id = ? and signer_id = ?",
+        let prev_opinion_result = sqlx::query_as::<DB, (Id<Opinion>, Date, u8, u8)>(
+            "select id,date,serial,format from opinion where statement_id = ? and signer_id = ?",
         )
         .bind(statement_id)
         .bind(signer_result.id)
         .fetch_optional(&self.pool)
         .await?;
-        if let Some((old_id, date, serial)) = prev_opinion_result {
+        if let Some((old_id, date, serial, format)) = prev_opinion_result {
+            // the same opinion may arrive re-signed in the version 1 format, keep the newer format then
             if date < opinion_data.date
                 || (date == opinion_data.date && serial < opinion_data.serial)
+                || (date == opinion_data.date
+                    && serial == opinion_data.serial
+                    && format < opinion.format as u8)
             {
                 // delete old, overridden opinion
                 sqlx::query("delete from opinion where id = ?")
@@ -390,14 +545,16 @@ impl Storage {
             }
         }
         let mut tx = self.pool.begin().await.unwrap();
-        sqlx::query("insert into opinion(statement_id, signer_id, date, valid, serial, certainty, signature) values(?,?,?,?,?,?,?)")
+        sqlx::query("insert into opinion(statement_id, signer_id, date, valid, serial, certainty, comment, signature, format) values(?,?,?,?,?,?,?,?,?)")
             .bind(statement_id)
             .bind(signer_result.id)
             .bind(opinion_data.date)
             .bind(opinion_data.valid)
             .bind(opinion_data.serial)
             .bind(opinion_data.certainty)
+            .bind(&opinion_data.comment)
             .bind(base64::encode(&opinion.signature))
+            .bind(opinion.format as u8)
             .execute(&mut tx)
             .await
             .expect("insert signed opinion");
@@ -406,9 +563,20 @@ impl Storage {
             .fetch_one(&mut tx)
             .await?;
         tx.commit().await?;
+        if self.is_trust_statement(statement_id).await? {
+            self.update_trust().await?;
+        }
         Ok(PersistResult::new(id, opinion))
     }
 
+    async fn is_trust_statement(&self, id: &Id<Statement>) -> Result<bool, Error> {
+        let name = sqlx::query_as::<DB, (String,)>("select name from statement where id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(matches!(name, Some((name,)) if name == "trusts"))
+    }
+
     pub async fn sign_statement_default(
         &mut self,
         statement: Statement,
@@ -421,7 +589,7 @@ impl Storage {
             certainty: 3,
             comment: "".into(),
         };
-        let signed_opinion = opinion.sign_using(&statement.signable_bytes(), &own_key.key);
+        let signed_opinion = opinion.sign_using(&statement, &own_key.key);
         let statement_id = self.persist(statement).await?.id;
         self.persist_opinion(signed_opinion, &statement_id).await
     }
@@ -455,7 +623,145 @@ impl Storage {
         Ok(statements)
     }
 
-    pub async fn ensure_own_key(&mut self) -> Result<(), Error> {
+    /// Weight of opinions made by `signer` when computing scores.
+    /// Signers below the trust threshold are ignored. Without a configured default trust,
+    /// signers outside our web of trust are fully trusted as long as we have no web of trust at all.
+    pub fn signer_trust(&self, signer: &PublicKey) -> f64 {
+        let trust = match (self.trust.get(signer), self.score_settings.default_trust) {
+            (Some(trust), _) => *trust,
+            (None, Some(default_trust)) => default_trust,
+            (None, None) if self.has_web_of_trust() => 0.0,
+            (None, None) => 1.0,
+        };
+        if trust < self.trust_settings.threshold {
+            0.0
+        } else {
+            trust
+        }
+    }
+
+    pub async fn set_trust_settings(&mut self, settings: TrustSettings) -> Result<(), Error> {
+        self.trust_settings = settings;
+        self.update_trust().await
+    }
+
+    /// Recompute the effective trust in all signers from the current trusts(Signer,Signer) opinions.
+    /// Only opinions signed by the trusting signer itself are taken into account.
+    pub async fn update_trust(&mut self) -> Result<(), Error> {
+        let today = Date::today();
+        let rows = sqlx::query_as::<DB, (String, String, Id<Statement>, i8)>(
+            "select truster.entity, trusted.entity, o.signer_id, o.certainty
+            from statement s
+            join statement_entity truster on s.id = truster.statement_id and truster.position = 0
+            join statement_entity trusted on s.id = trusted.statement_id and trusted.position = 1
+            join opinion o on s.id = o.statement_id
+            where s.name = 'trusts' and o.date <= ? and o.date + o.valid >= ?",
+        )
+        .bind(today)
+        .bind(today)
+        .fetch_all(&self.pool)
+        .await?;
+        let edges = rows
+            .into_iter()
+            .filter_map(|(truster, trusted, signer_id, certainty)| {
+                let truster = PublicKey::from_str(&truster).ok()?;
+                if self.signers.get(&signer_id) != Some(&truster) {
+                    return None;
+                }
+                Some(TrustEdge {
+                    truster,
+                    trusted: PublicKey::from_str(&trusted).ok()?,
+                    certainty,
+                })
+            })
+            .collect::<Vec<_>>();
+        if let Entity::Signer(own) = &self.own_key.signer {
+            self.trust = compute_trust(own, &edges, &self.trust_settings);
+        }
+        debug!("trust in {} signers", self.trust.len());
+        Ok(())
+    }
+
+    /// true if we trust or distrust any signer besides our own key
+    pub fn has_web_of_trust(&self) -> bool {
+        self.trust.len() > 1
+    }
+
+    /// The effective trust of all signers in our web of trust
+    pub fn trusted_signers(&self) -> Vec<(PublicKey, f64)> {
+        self.trust
+            .iter()
+            .map(|(signer, trust)| (signer.clone(), *trust))
+            .sorted_by(|a, b| b.1.total_cmp(&a.1))
+            .collect()
+    }
+
+    /// All known templates with the id of their template() statement
+    pub fn templates(&self) -> Vec<(Id<Statement>, Template)> {
+        self.templates
+            .iter()
+            .map(|(id, template)| (*id, template.clone()))
+            .sorted_by_key(|(id, _)| *id)
+            .collect()
+    }
+
+    /// All known signers with the id of their signer() statement and their trust, if they are in our web of trust
+    pub fn signers(&self) -> Vec<(Id<Statement>, PublicKey, Option<f64>)> {
+        self.signers
+            .iter()
+            .map(|(id, signer)| (*id, signer.clone(), self.trust.get(signer).copied()))
+            .sorted_by_key(|(id, _, _)| *id)
+            .collect()
+    }
+
+    /// Number of records in the database
+    pub async fn stats(&self) -> Result<Stats, Error> {
+        let (statements, opinions, peers) = sqlx::query_as::<DB, (i64, i64, i64)>(
+            "select
+                (select count(*) from statement),
+                (select count(*) from opinion),
+                (select count(*) from peer)",
+        )
+        .fetch_one(&self.pool)
+        .await?;
+        Ok(Stats {
+            statements,
+            opinions,
+            templates: self.templates.len(),
+            signers: self.signers.len(),
+            peers,
+        })
+    }
+
+    pub fn set_score_settings(&mut self, settings: ScoreSettings) {
+        self.score_settings = settings;
+    }
+
+    /// Combined score of all current opinions on a statement
+    pub async fn score_statement(&self, id: Id<Statement>) -> Result<Score, Error> {
+        let opinions = self.list_opinions_on(id).await?;
+        Ok(Score::of(
+            opinions.iter().map(|opinion| &opinion.data),
+            Date::today(),
+            &self.score_settings,
+            |signer| self.signer_trust(signer),
+        ))
+    }
+
+    /// All statements about an entity together with their scores
+    pub async fn score_statements_about(
+        &self,
+        entity: &Entity,
+    ) -> Result<Vec<(Persistent<Statement>, Score)>, Error> {
+        let mut result = vec![];
+        for statement in self.find_statements_about(entity).await? {
+            let score = self.score_statement(statement.id).await?;
+            result.push((statement, score));
+        }
+        Ok(result)
+    }
+
+    pub async fn ensure_own_key(&mut self, new_key: &NewKey) -> Result<(), Error> {
         self.own_key = match sqlx::query_as::<DB, DbPrivateKey>(&format!(
             "select {} from {}",
             DbPrivateKey::COLUMNS,
@@ -465,33 +771,31 @@ impl Storage {
         .await?
         {
             Some(private_key) => {
+                let algorithm =
+                    KeyAlgorithm::from_str(&private_key.algorithm).expect("key algorithm");
                 let key_bytes = base64::decode(private_key.key).expect("base64 decode");
-                let privkey = libp2p::identity::secp256k1::SecretKey::from_bytes(key_bytes)
-                    .expect("secp256k1 decode");
-                debug!("getting statement with id {}", private_key.signer_id);
-                let statement = self.get(private_key.signer_id).await?.unwrap();
-                let signer = statement.entities[0].clone();
-                let keypair =
-                    Keypair::Secp256k1(libp2p::identity::secp256k1::Keypair::from(privkey));
-                OwnKey {
-                    signer: signer,
-                    level: 0,
-                    key: keypair,
-                }
+                debug!(
+                    "using {} key of signer with id {}",
+                    algorithm, private_key.signer_id
+                );
+                OwnKey::decode(algorithm, &key_bytes).expect("private key decode")
             }
             _ => {
-                let own_key = OwnKey::new();
+                let own_key = new_key
+                    .create()
+                    .map_err(|e| Error::Configuration(Box::new(e)))?;
                 let statement = Statement::signer(own_key.signer.clone());
                 let persist_result = self.persist(statement).await?;
                 let privkey = own_key.privkey_string();
                 info!("trust {} {}", persist_result.id, privkey);
                 let mut tx = self.pool.begin().await.unwrap();
                 sqlx::query(&format!(
-                    "insert into {} (signer_id, key) values(?,?)",
+                    "insert into {} (signer_id, key, algorithm) values(?,?,?)",
                     DbPrivateKey::TABLE
                 ))
                 .bind(persist_result.id)
                 .bind(privkey)
+                .bind(own_key.algorithm().to_string())
                 .execute(&mut tx)
                 .await?;
                 tx.commit().await?;
@@ -505,12 +809,215 @@ impl Storage {
         &self.own_key
     }
 
-    /// Refresh opinions that would expire soon but should still be valid.
-    /// Currently only refreshes templates.
+    /// Renew own opinions expiring within this many days
+    pub fn set_refresh_horizon(&mut self, days: u16) {
+        self.refresh_horizon = days;
+    }
+
+    /// Refresh own opinions that would expire soon but should still be valid, i.e. were not withdrawn
+    /// by signing them with certainty 0. Opinions which have expired already stay expired.
+    /// The renewed opinions are dated today and otherwise unchanged.
     /// Returns a list of statements to be published to the network.
-    #[allow(dead_code)]
-    pub async fn refresh_opinions(&self) -> Result<Vec<SignedStatement>, Error> {
-        Ok(vec![])
+    pub async fn refresh_opinions(&mut self) -> Result<Vec<SignedStatement>, Error> {
+        let own_key = self.own_key.clone();
+        let signer_id = self.own_signer_id().await?;
+        let today = Date::today();
+        let rows = sqlx::query_as::<DB, (Id<Statement>, u16, i8, Option<String>)>(
+            "select statement_id, valid, certainty, comment from opinion
+            where signer_id = ? and certainty <> 0 and date < ? and date + valid between ? and ?",
+        )
+        .bind(signer_id)
+        .bind(today)
+        .bind(today)
+        .bind(today + self.refresh_horizon)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut result = vec![];
+        for (statement_id, valid, certainty, comment) in rows {
+            let statement = match self.get(statement_id).await? {
+                Some(statement) => statement.data,
+                None => continue,
+            };
+            let opinion = UnsignedOpinion {
+                date: today,
+                valid,
+                serial: self.next_serial(statement_id, signer_id, today).await?,
+                certainty,
+                comment: comment.unwrap_or_default(),
+            }
+            .sign_using(&statement, &own_key.key);
+            self.persist_opinion(opinion.clone(), &statement_id).await?;
+            debug!("refreshed opinion on {}", statement);
+            result.push(SignedStatement::new(statement, vec![opinion]));
+        }
+        Ok(result)
+    }
+
+    /// Retract our own opinion on a statement by signing a superseding one with certainty 0.
+    /// It stays valid as long as the retracted opinion would have, so that copies of that
+    /// still held by other nodes cannot override it. Returns None if we have no opinion to retract.
+    pub async fn retract_statement(
+        &mut self,
+        statement: Statement,
+    ) -> Result<Option<SignedStatement>, Error> {
+        let statement = if self.requires_email_hashing(&statement) {
+            statement.hash_emails()
+        } else {
+            statement
+        };
+        let statement_id = match self.try_select_statement(&statement).await? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let own_key = self.own_key.clone();
+        let signer_id = self.own_signer_id().await?;
+        let current = sqlx::query_as::<DB, (Date, u16, i8)>(
+            "select date, valid, certainty from opinion where statement_id = ? and signer_id = ?",
+        )
+        .bind(statement_id)
+        .bind(signer_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        let last_date = match current {
+            Some((date, valid, certainty)) if certainty != 0 => date + valid,
+            _ => return Ok(None),
+        };
+        let today = Date::today();
+        let opinion = UnsignedOpinion {
+            date: today,
+            valid: last_date.d.saturating_sub(today.d).clamp(1, u16::MAX as u32) as u16,
+            serial: self.next_serial(statement_id, signer_id, today).await?,
+            certainty: 0,
+            comment: "".into(),
+        }
+        .sign_using(&statement, &own_key.key);
+        self.persist_opinion(opinion.clone(), &statement_id).await?;
+        Ok(Some(SignedStatement::new(statement, vec![opinion])))
+    }
+
+    /// Sign a statement with our own key. An opinion of ours from the same day is superseded
+    /// by giving the new one the next serial.
+    pub async fn sign_own_opinion(
+        &mut self,
+        statement_id: &Id<Statement>,
+        statement: &Statement,
+        opinion: UnsignedOpinion,
+    ) -> Result<PersistResult<Opinion>, Error> {
+        let signer_id = self.own_signer_id().await?;
+        let opinion = UnsignedOpinion {
+            serial: self.next_serial(*statement_id, signer_id, opinion.date).await?,
+            ..opinion
+        }
+        .sign_using(statement, &self.own_key.key);
+        self.persist_opinion(opinion, statement_id).await
+    }
+
+    async fn own_signer_id(&mut self) -> Result<Id<Statement>, Error> {
+        let signer = Statement::signer(self.own_key.signer.clone());
+        Ok(self.persist(signer).await?.id)
+    }
+
+    /// The serial for a new opinion of a signer on a statement: 0, unless the signer already has an opinion
+    /// on the statement from the same date, which the new one has to supersede. That fails once all serials
+    /// of the date are used up.
+    async fn next_serial(
+        &self,
+        statement_id: Id<Statement>,
+        signer_id: Id<Statement>,
+        date: Date,
+    ) -> Result<u8, Error> {
+        let serial = sqlx::query_as::<DB, (u8,)>(
+            "select serial from opinion where statement_id = ? and signer_id = ? and date = ?",
+        )
+        .bind(statement_id)
+        .bind(signer_id)
+        .bind(date)
+        .fetch_optional(&self.pool)
+        .await?;
+        match serial {
+            Some((serial,)) => serial.checked_add(1).ok_or_else(|| {
+                Error::Protocol(format!(
+                    "no serial left for another opinion on {} on {}",
+                    statement_id, date
+                ))
+            }),
+            None => Ok(0),
+        }
+    }
+
+    /// Only return opinions with valid signatures when reading them from the database
+    pub fn set_verify_on_load(&mut self, verify: bool) {
+        self.verify_on_load = verify;
+    }
+
+    /// Check the signatures of all stored opinions and return those which do not verify.
+    /// With `quarantine`, broken opinions are moved to the opinion_quarantine table.
+    pub async fn verify_opinions(
+        &mut self,
+        quarantine: bool,
+    ) -> Result<Vec<(Statement, Opinion)>, Error> {
+        let rows = sqlx::query_as::<DB, DbOpinion>(&format!(
+            "select {} from {} order by statement_id",
+            DbOpinion::COLUMNS,
+            DbOpinion::TABLE
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        let mut broken = vec![];
+        let mut statement: Option<Persistent<Statement>> = None;
+        for row in rows {
+            if statement.as_ref().map(|s| s.id) != Some(row.statement_id) {
+                statement = self.get(row.statement_id).await?;
+            }
+            let statement = match &statement {
+                Some(statement) => statement,
+                None => continue,
+            };
+            let id = row.id;
+            let opinion = Opinion::from_using_storage(row, self).await;
+            if opinion.verify_signature(statement) {
+                continue;
+            }
+            if quarantine {
+                self.quarantine_opinion(id, statement, &opinion).await?;
+            }
+            broken.push((statement.data.clone(), opinion));
+        }
+        if quarantine && !broken.is_empty() {
+            self.update_trust().await?;
+        }
+        Ok(broken)
+    }
+
+    // move an opinion with a broken signature to the opinion_quarantine table
+    async fn quarantine_opinion(
+        &self,
+        id: Id<Opinion>,
+        statement: &Statement,
+        opinion: &Opinion,
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "insert into opinion_quarantine(statement, signer, date, valid, serial, certainty, comment, signature, format, quarantined)
+            values(?,?,?,?,?,?,?,?,?,?)",
+        )
+        .bind(statement.to_string())
+        .bind(opinion.signer.to_string())
+        .bind(opinion.date)
+        .bind(opinion.valid)
+        .bind(opinion.serial)
+        .bind(opinion.certainty)
+        .bind(&opinion.comment)
+        .bind(base64::encode(&opinion.signature))
+        .bind(opinion.format as u8)
+        .bind(Date::today())
+        .execute(&mut tx)
+        .await?;
+        sqlx::query("delete from opinion where id = ?")
+            .bind(id)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await
     }
 
     /// Clean up opinions which are not valid anymore.
@@ -545,15 +1052,189 @@ impl Storage {
 
     /// utility method to fix the cidr database columns, to be used if there are old IP entries
     pub async fn fix_cidr(&self) -> Result<(), Error> {
-        for s in self.get_all().await? {
-            let (cidr_min, cidr_max) = s.entities[0].cidr_minmax();
-            if let Some(cidr_min) = cidr_min {
-                sqlx::query("update statement set cidr_min=?, cidr_max=? where id=?")
-                    .bind(cidr_min)
-                    .bind(cidr_max)
-                    .bind(s.id)
+        self.fix_statement_entities(true).await
+    }
+
+    /// Fill in entity type and cidr columns of statement entities, either for all of them
+    /// or only for those which were migrated from the old fixed-column statement table.
+    pub async fn fix_statement_entities(&self, all: bool) -> Result<(), Error> {
+        let rows = sqlx::query_as::<DB, (Id<Statement>, u32, String)>(&format!(
+            "select statement_id, position, entity from statement_entity{}",
+            if all { "" } else { " where entity_type is null" }
+        ))
+        .fetch_all(&self.pool)
+        .await?;
+        let mut tx = self.pool.begin().await?;
+        for (statement_id, position, entity) in rows {
+            let entity = match Entity::from_str(&entity) {
+                Ok(entity) => entity,
+                Err(_) => {
+                    error!("could not parse entity {} of statement {}", entity, statement_id);
+                    continue;
+                }
+            };
+            let (cidr_min, cidr_max) = entity.cidr_minmax();
+            sqlx::query(
+                "update statement_entity set entity_type=?, cidr_min=?, cidr_max=?
+                where statement_id=? and position=?",
+            )
+            .bind(entity.entity_type() as u8)
+            .bind(cidr_min)
+            .bind(cidr_max)
+            .bind(statement_id)
+            .bind(position)
+            .execute(&mut tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    /// Migration step for statements stored before their entities were normalized (e.g. domain case and IDNA).
+    /// Statements which become duplicates are merged into the existing normalized statement,
+    /// keeping the most recent opinion of each signer.
+    /// Signatures were made over the old wording, so our own opinions on changed statements are re-signed
+    /// and foreign opinions which do not verify anymore are quarantined.
+    /// Returns the number of changed statements.
+    pub async fn normalize_statements(&mut self) -> Result<usize, Error> {
+        let rows = sqlx::query_as::<DB, (Id<Statement>, String)>("select id, content from statement")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut changed = vec![];
+        for (id, content) in rows {
+            let normalized = match Statement::from_str(&content) {
+                Ok(statement) => statement,
+                Err(_) => {
+                    error!("could not parse statement {}: {}", id, content);
+                    continue;
+                }
+            };
+            let normalized_content = normalized.to_string();
+            if normalized_content == content {
+                continue;
+            }
+            info!("normalizing statement {}: {} -> {}", id, content, normalized_content);
+            let mut tx = self.pool.begin().await?;
+            let existing =
+                sqlx::query_as::<DB, (Id<Statement>,)>("select id from statement where content = ?")
+                    .bind(&normalized_content)
+                    .fetch_optional(&mut tx)
+                    .await?;
+            changed.push(existing.map_or(id, |(target,)| target));
+            match existing {
+                Some((target,)) => {
+                    // drop the older opinion where both statements have one by the same signer
+                    sqlx::query(
+                        "delete from opinion
+                        where statement_id = ? and exists (
+                            select 1 from opinion o
+                            where o.statement_id = ? and o.signer_id = opinion.signer_id
+                            and (o.date > opinion.date or (o.date = opinion.date and o.serial >= opinion.serial)))",
+                    )
+                    .bind(id)
+                    .bind(target)
+                    .execute(&mut tx)
+                    .await?;
+                    sqlx::query(
+                        "delete from opinion
+                        where statement_id = ? and exists (
+                            select 1 from opinion o
+                            where o.statement_id = ? and o.signer_id = opinion.signer_id)",
+                    )
+                    .bind(target)
+                    .bind(id)
+                    .execute(&mut tx)
+                    .await?;
+                    sqlx::query("update opinion set statement_id = ? where statement_id = ?")
+                        .bind(target)
+                        .bind(id)
+                        .execute(&mut tx)
+                        .await?;
+                    sqlx::query("update opinion set signer_id = ? where signer_id = ?")
+                        .bind(target)
+                        .bind(id)
+                        .execute(&mut tx)
+                        .await?;
+                    sqlx::query("update private_key set signer_id = ? where signer_id = ?")
+                        .bind(target)
+                        .bind(id)
+                        .execute(&mut tx)
+                        .await?;
+                    sqlx::query("delete from statement_entity where statement_id = ?")
+                        .bind(id)
+                        .execute(&mut tx)
+                        .await?;
+                    sqlx::query("delete from statement where id = ?")
+                        .bind(id)
+                        .execute(&mut tx)
+                        .await?;
+                }
+                None => {
+                    sqlx::query("update statement set content = ? where id = ?")
+                        .bind(&normalized_content)
+                        .bind(id)
+                        .execute(&mut tx)
+                        .await?;
+                    for (position, entity) in normalized.entities.iter().enumerate() {
+                        sqlx::query(
+                            "update statement_entity set entity = ? where statement_id = ? and position = ?",
+                        )
+                        .bind(entity.to_string())
+                        .bind(id)
+                        .bind(position as u32)
+                        .execute(&mut tx)
+                        .await?;
+                    }
+                }
+            }
+            tx.commit().await?;
+        }
+        if !changed.is_empty() {
+            self.fix_statement_entities(true).await?;
+            self.templates.clear();
+            self.signers.clear();
+            self.read_templates().await?;
+            self.read_signers().await?;
+            changed.sort();
+            changed.dedup();
+            for id in &changed {
+                self.resign_opinions_on(*id).await?;
+            }
+            self.update_trust().await?;
+        }
+        Ok(changed.len())
+    }
+
+    // re-sign our own opinions on a statement whose wording changed, quarantine foreign ones which do not verify
+    async fn resign_opinions_on(&mut self, id: Id<Statement>) -> Result<(), Error> {
+        let statement = match self.get(id).await? {
+            Some(statement) => statement.data,
+            None => return Ok(()),
+        };
+        let rows = sqlx::query_as::<DB, DbOpinion>(&format!(
+            "select {} from {} where statement_id = ?",
+            DbOpinion::COLUMNS,
+            DbOpinion::TABLE
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?;
+        for row in rows {
+            let opinion_id = row.id;
+            let opinion = Opinion::from_using_storage(row, self).await;
+            if opinion.verify_signature(&statement) {
+                continue;
+            }
+            if Entity::Signer(opinion.signer.clone()) == self.own_key.signer {
+                let resigned = opinion.data.sign_using(&statement, &self.own_key.key);
+                sqlx::query("update opinion set signature = ?, format = ? where id = ?")
+                    .bind(base64::encode(&resigned.signature))
+                    .bind(resigned.format as u8)
+                    .bind(opinion_id)
                     .execute(&self.pool)
                     .await?;
+            } else {
+                info!("quarantining opinion on {} by {}", statement, opinion.signer);
+                self.quarantine_opinion(opinion_id, &statement, &opinion).await?;
             }
         }
         Ok(())
@@ -590,15 +1271,523 @@ mod tests {
 
     use super::*;
 
+    /// sign a template with our own key, so that statements using it can be stored
+    fn add_template(storage: &mut Storage, template: &str) -> OwnKey {
+        let own_key = storage.own_key().clone();
+        let statement = Statement::from_str(&format!("template({})", template)).unwrap();
+        block_on(storage.sign_statement_default(statement, &own_key)).unwrap();
+        own_key
+    }
+
     #[test]
     fn lookup_statement() {
-        let mut storage = block_on(Storage::new());
-        block_on(storage.initialize_database()).expect("could initialize database");
+        let mut storage = block_on(Storage::temporary());
+        block_on(storage.initialize_database(&NewKey::default()))
+            .expect("could initialize database");
         let statement = Statement::from_str("template(template(Template))").unwrap();
         let persist_result = block_on(storage.persist(statement)).unwrap();
         assert!(persist_result.id >= Id::new(1));
     }
 
+    #[test]
+    fn five_entity_statement() {
+        let mut storage = block_on(Storage::temporary());
+        let template =
+            Statement::from_str("template(netblock(IPv4,AS,Domain,EMail,Url))").unwrap();
+        block_on(storage.persist(template)).unwrap();
+        let statement = Statement::from_str(
+            "netblock(192.0.2.0/24,AS64496,example.net,abuse@example.net,https://example.net/abuse)",
+        )
+        .unwrap();
+        let persist_result = block_on(storage.persist(statement.clone())).unwrap();
+        let id = persist_result.id;
+        assert_eq!(block_on(storage.persist(statement.clone())).unwrap().id, id);
+        let fetched = block_on(storage.get(id)).unwrap().unwrap();
+        assert_eq!(fetched.data, statement);
+        for entity in ["192.0.2.77", "AS64496", "https://example.net/abuse"] {
+            let found = block_on(storage.find_statements_about(&Entity::from_str(entity).unwrap()))
+                .unwrap();
+            assert!(found.iter().any(|s| s.id == id), "lookup via {}", entity);
+        }
+    }
+
+    #[test]
+    fn score() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(score.example.com)").unwrap();
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        block_on(storage.sign_statement_default(statement, &own_key)).unwrap();
+        let score = block_on(storage.score_statement(id)).unwrap();
+        assert_eq!(score.value, 3.0);
+        assert_eq!(score.opinions, 1);
+        let scored = block_on(
+            storage.score_statements_about(&Entity::from_str("www.score.example.com").unwrap()),
+        )
+        .unwrap();
+        assert!(scored.iter().any(|(s, score)| s.id == id && score.is_positive()));
+    }
+
+    #[test]
+    fn suffix_statements() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "registry(Domain[suffix])");
+        let statement = Statement::from_str("registry(co.uk)").unwrap();
+        assert!(storage.has_matching_template(&statement));
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        block_on(storage.sign_statement_default(statement, &own_key)).unwrap();
+        let found =
+            block_on(storage.find_statements_about(&Entity::from_str("co.uk").unwrap())).unwrap();
+        assert!(found.iter().any(|s| s.id == id));
+        // public suffixes are not looked up for the domains registered below them
+        let found = block_on(
+            storage.find_statements_about(&Entity::from_str("www.example.co.uk").unwrap()),
+        )
+        .unwrap();
+        assert!(!found.iter().any(|s| s.id == id));
+    }
+
+    #[test]
+    fn web_of_trust() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = storage.own_key().clone();
+        let other = PublicKey {
+            key: libp2p::identity::Keypair::generate_ed25519().public(),
+        };
+        storage.set_score_settings(ScoreSettings {
+            decay: true,
+            default_trust: Some(0.0),
+        });
+        assert_eq!(storage.signer_trust(&other), 0.0);
+        let statement =
+            Statement::from_str(&format!("trusts({},{})", own_key.signer, other)).unwrap();
+        block_on(storage.sign_statement_default(statement, &own_key)).unwrap();
+        assert_eq!(storage.signer_trust(&other), 1.0);
+        assert!(storage.trusted_signers().iter().any(|(s, _)| *s == other));
+    }
+
+    #[test]
+    fn without_web_of_trust() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = storage.own_key().clone();
+        let key = || PublicKey {
+            key: libp2p::identity::Keypair::generate_ed25519().public(),
+        };
+        let (other, stranger) = (key(), key());
+        // until we trust anybody, opinions of all signers count
+        assert!(!storage.has_web_of_trust());
+        assert_eq!(storage.signer_trust(&stranger), 1.0);
+        storage.set_score_settings(ScoreSettings {
+            decay: true,
+            default_trust: Some(0.0),
+        });
+        assert_eq!(storage.signer_trust(&stranger), 0.0);
+        storage.set_score_settings(ScoreSettings::default());
+        let statement =
+            Statement::from_str(&format!("trusts({},{})", own_key.signer, other)).unwrap();
+        block_on(storage.sign_statement_default(statement, &own_key)).unwrap();
+        assert!(storage.has_web_of_trust());
+        assert_eq!(storage.signer_trust(&stranger), 0.0);
+    }
+
+    #[test]
+    fn normalize_statements() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(normalize.example.com)").unwrap();
+        let target = block_on(storage.persist(statement.clone())).unwrap().id;
+        block_on(storage.sign_statement_default(statement, &own_key)).unwrap();
+        let signer_id = block_on(storage.persist(Statement::signer(own_key.signer.clone())))
+            .unwrap()
+            .id;
+
+        // statements as they might have been stored before normalization
+        for (content, entity) in [
+            ("spammer(NORMALIZE.example.com)", "NORMALIZE.example.com"),
+            ("spammer(Renamed.Example.COM.)", "Renamed.Example.COM."),
+        ] {
+            let id = block_on(
+                sqlx::query_as::<DB, (Id<Statement>,)>(
+                    "insert into statement(name, content) values('spammer', ?)
+                    on conflict(content) do update set name = name returning id",
+                )
+                .bind(content)
+                .fetch_one(&storage.pool),
+            )
+            .unwrap()
+            .0;
+            block_on(
+                sqlx::query(
+                    "insert or ignore into statement_entity(statement_id, position, entity) values(?, 0, ?)",
+                )
+                .bind(id)
+                .bind(entity)
+                .execute(&storage.pool),
+            )
+            .unwrap();
+            block_on(
+                sqlx::query(
+                    "insert or ignore into opinion(statement_id, signer_id, date, valid, serial, certainty, signature)
+                    values(?, ?, ?, 30, 0, 1, 'AA==')",
+                )
+                .bind(id)
+                .bind(signer_id)
+                .bind(Date::from(Date::today().d - 1))
+                .execute(&storage.pool),
+            )
+            .unwrap();
+        }
+        // a foreign opinion, which can't be re-signed
+        let other = PublicKey {
+            key: libp2p::identity::Keypair::generate_ed25519().public(),
+        };
+        let other_id = block_on(storage.persist(Statement::signer(Entity::Signer(other))))
+            .unwrap()
+            .id;
+        block_on(
+            sqlx::query(
+                "insert into opinion(statement_id, signer_id, date, valid, serial, certainty, signature)
+                select id, ?, ?, 30, 0, 1, 'AA==' from statement where content = 'spammer(Renamed.Example.COM.)'",
+            )
+            .bind(other_id)
+            .bind(Date::today())
+            .execute(&storage.pool),
+        )
+        .unwrap();
+
+        block_on(storage.normalize_statements()).unwrap();
+        storage.set_verify_on_load(true);
+        let opinions = block_on(storage.list_opinions_on(target)).unwrap();
+        assert_eq!(opinions.len(), 1);
+        assert_eq!(opinions[0].date, Date::today());
+        let renamed = Statement::from_str("spammer(renamed.example.com)").unwrap();
+        let found = block_on(storage.find_statements_about(&renamed.entities[0])).unwrap();
+        let renamed_id = found.iter().find(|s| s.data == renamed).unwrap().id;
+        assert!(block_on(storage.try_select_statement(&renamed)).unwrap().is_some());
+
+        // our opinion was re-signed over the new wording, the foreign one was quarantined
+        let opinions = block_on(storage.list_opinions_on(renamed_id)).unwrap();
+        assert_eq!(opinions.len(), 1);
+        assert_eq!(Entity::Signer(opinions[0].signer.clone()), own_key.signer);
+        assert!(block_on(storage.verify_opinions(false)).unwrap().is_empty());
+        let quarantined = block_on(
+            sqlx::query_scalar::<DB, i64>(
+                "select count(*) from opinion_quarantine where statement = 'spammer(renamed.example.com)'",
+            )
+            .fetch_one(&storage.pool),
+        )
+        .unwrap();
+        assert_eq!(quarantined, 1);
+    }
+
+    #[test]
+    fn opinion_comment_and_verification() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(comment.example.com)").unwrap();
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        let opinion = UnsignedOpinion {
+            comment: "seen in; spam trap".into(),
+            ..UnsignedOpinion::default()
+        }
+        .sign_using(&statement, &own_key.key);
+        block_on(storage.persist_opinion(opinion, &id)).unwrap();
+
+        storage.set_verify_on_load(true);
+        let opinions = block_on(storage.list_opinions_on(id)).unwrap();
+        assert_eq!(opinions.len(), 1);
+        assert_eq!(opinions[0].comment, "seen in; spam trap");
+        assert!(opinions[0].verify_signature(&statement));
+
+        // tamper with the stored opinion
+        block_on(
+            sqlx::query("update opinion set certainty = -3 where statement_id = ?")
+                .bind(id)
+                .execute(&storage.pool),
+        )
+        .unwrap();
+        assert!(block_on(storage.list_opinions_on(id)).unwrap().is_empty());
+        let broken = block_on(storage.verify_opinions(true)).unwrap();
+        assert!(broken.iter().any(|(s, _)| *s == statement));
+        storage.set_verify_on_load(false);
+        assert!(block_on(storage.list_opinions_on(id)).unwrap().is_empty());
+    }
+
+    #[test]
+    fn opinion_format_upgrade() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(format.example.com)").unwrap();
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        let opinion = UnsignedOpinion::default();
+        let v1 = opinion
+            .clone()
+            .sign_using_format(&statement, &own_key.key, SignatureFormat::V1);
+        let v2 = opinion.sign_using(&statement, &own_key.key);
+        let result = block_on(storage.persist_opinion(v2.clone(), &id)).unwrap();
+        assert!(result.is_new());
+        // an opinion re-signed for version 1 nodes does not replace the original
+        let result = block_on(storage.persist_opinion(v1.clone(), &id)).unwrap();
+        assert!(!result.is_new());
+        let opinions = block_on(storage.list_opinions_on(id)).unwrap();
+        assert_eq!(opinions[0].format, SignatureFormat::V2);
+        // but the original replaces it
+        block_on(
+            sqlx::query("delete from opinion where statement_id = ?")
+                .bind(id)
+                .execute(&storage.pool),
+        )
+        .unwrap();
+        block_on(storage.persist_opinion(v1, &id)).unwrap();
+        let result = block_on(storage.persist_opinion(v2, &id)).unwrap();
+        assert!(result.is_new());
+        let opinions = block_on(storage.list_opinions_on(id)).unwrap();
+        assert_eq!(opinions.len(), 1);
+        assert_eq!(opinions[0].format, SignatureFormat::V2);
+    }
+
+    #[test]
+    fn statements_with_signatures() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(reconcile.example.com)").unwrap();
+        let signed = block_on(storage.sign_statement_default(statement.clone(), &own_key)).unwrap();
+        let signature = base64::encode(&signed.data.signature);
+
+        let signatures = block_on(storage.list_signatures("spammer", Date::today())).unwrap();
+        assert!(signatures.contains(&signature));
+        assert!(signatures.windows(2).all(|w| w[0] <= w[1]));
+        let found =
+            block_on(storage.list_statements_with_signatures(std::slice::from_ref(&signature))).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].statement, statement);
+        assert_eq!(found[0].opinions.len(), 1);
+        assert!(found[0].opinions[0].verify_signature(&statement));
+    }
+
+    #[test]
+    fn statements_with_several_entities_and_opinions() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "announces(AS,IPv4,Domain)");
+        let statement = Statement::from_str("announces(AS64496,192.0.2.0/24,example.net)").unwrap();
+        block_on(storage.sign_statement_default(statement.clone(), &own_key)).unwrap();
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        let other_key = libp2p::identity::Keypair::generate_ed25519();
+        let other = UnsignedOpinion::default().sign_using(&statement, &other_key);
+        block_on(storage.persist_opinion(other, &id)).unwrap();
+
+        let found =
+            block_on(storage.list_statements_named_signed("announces", Date::today())).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].statement, statement);
+        assert_eq!(found[0].opinions.len(), 2);
+        assert!(found[0].verify_signatures());
+        let about =
+            block_on(storage.find_statements_about(&Entity::from_str("192.0.2.1").unwrap()))
+                .unwrap();
+        assert_eq!(about.len(), 1);
+        assert_eq!(about[0].data, statement);
+    }
+
+    #[test]
+    fn paginated_templates() {
+        let mut storage = block_on(Storage::temporary());
+        add_template(&mut storage, "paginated(Domain)");
+        let mut found = vec![];
+        let mut after = Id::new(0);
+        loop {
+            let page = block_on(storage.list_templates_after(after, 2)).unwrap();
+            assert!(page.len() <= 2);
+            match page.last() {
+                Some((id, _)) => after = *id,
+                None => break,
+            }
+            found.extend(page.into_iter().map(|(_, entity)| entity));
+        }
+        assert_eq!(found, block_on(storage.list_all_templates()).unwrap());
+    }
+
+    #[test]
+    fn active_days() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        assert_eq!(block_on(storage.active_days()).unwrap(), 1);
+        for (content, age, valid) in [
+            ("spammer(active.example.com)", 90, 100),
+            ("spammer(expired.example.com)", 200, 100),
+        ] {
+            let statement = Statement::from_str(content).unwrap();
+            let id = block_on(storage.persist(statement.clone())).unwrap().id;
+            let opinion = UnsignedOpinion {
+                date: Date::from(Date::today().d - age),
+                valid,
+                serial: 0,
+                certainty: 3,
+                comment: "".into(),
+            }
+            .sign_using(&statement, &own_key.key);
+            block_on(storage.persist_opinion(opinion, &id)).unwrap();
+        }
+        assert_eq!(block_on(storage.active_days()).unwrap(), 91);
+    }
+
+    #[test]
+    fn refresh_opinions() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let mut ids = vec![];
+        for (content, certainty, age) in [
+            ("spammer(refresh.example.com)", 2, 25),
+            ("spammer(withdrawn.refresh.example.com)", 0, 25),
+            ("spammer(expired.refresh.example.com)", 2, 31),
+        ] {
+            let statement = Statement::from_str(content).unwrap();
+            let id = block_on(storage.persist(statement.clone())).unwrap().id;
+            let opinion = UnsignedOpinion {
+                date: Date::from(Date::today().d - age),
+                valid: 30,
+                serial: 0,
+                certainty,
+                comment: "renew me".into(),
+            }
+            .sign_using(&statement, &own_key.key);
+            block_on(storage.persist_opinion(opinion, &id)).unwrap();
+            ids.push(id);
+        }
+        let refreshed = block_on(storage.refresh_opinions()).unwrap();
+        let renewed = refreshed
+            .iter()
+            .find(|s| s.statement.to_string() == "spammer(refresh.example.com)")
+            .unwrap();
+        assert_eq!(renewed.opinions[0].data.date, Date::today());
+        assert_eq!(renewed.opinions[0].data.certainty, 2);
+        assert_eq!(renewed.opinions[0].data.comment, "renew me");
+        assert!(!refreshed
+            .iter()
+            .any(|s| s.statement.to_string() == "spammer(withdrawn.refresh.example.com)"));
+        assert!(!refreshed
+            .iter()
+            .any(|s| s.statement.to_string() == "spammer(expired.refresh.example.com)"));
+
+        let opinions = block_on(storage.list_opinions_on(ids[0])).unwrap();
+        assert_eq!(opinions.len(), 1);
+        assert_eq!(opinions[0].data.data.date, Date::today());
+        // nothing left to renew today
+        let refreshed = block_on(storage.refresh_opinions()).unwrap();
+        assert!(!refreshed
+            .iter()
+            .any(|s| s.statement.to_string() == "spammer(refresh.example.com)"));
+    }
+
+    #[test]
+    fn retract_statement() {
+        let mut storage = block_on(Storage::temporary());
+        let own_key = add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(retract.example.com)").unwrap();
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        block_on(storage.sign_statement_default(statement.clone(), &own_key)).unwrap();
+
+        let retraction = block_on(storage.retract_statement(statement.clone()))
+            .unwrap()
+            .unwrap();
+        let opinion = &retraction.opinions[0];
+        assert_eq!(opinion.data.certainty, 0);
+        assert_eq!(opinion.data.serial, 1);
+        assert_eq!(opinion.data.last_date(), Date::today() + 30);
+        assert!(opinion.verify_signature(&statement));
+        let score = block_on(storage.score_statement(id)).unwrap();
+        assert_eq!(score.opinions, 0);
+        // there is nothing left to retract
+        assert!(block_on(storage.retract_statement(statement)).unwrap().is_none());
+    }
+
+    #[test]
+    fn next_serial() {
+        let mut storage = block_on(Storage::temporary());
+        add_template(&mut storage, "spammer(Domain)");
+        let statement = Statement::from_str("spammer(serial.example.com)").unwrap();
+        let id = block_on(storage.persist(statement.clone())).unwrap().id;
+        let first = block_on(storage.sign_own_opinion(&id, &statement, UnsignedOpinion::default()))
+            .unwrap();
+        let second = block_on(storage.sign_own_opinion(
+            &id,
+            &statement,
+            UnsignedOpinion {
+                certainty: -1,
+                ..Default::default()
+            },
+        ))
+        .unwrap();
+        assert!(second.is_new());
+        assert_eq!(second.data.serial, first.data.serial + 1);
+        let opinions = block_on(storage.list_opinions_on(id)).unwrap();
+        assert_eq!(opinions.len(), 1);
+        assert_eq!(opinions[0].data.data.certainty, -1);
+
+        // the last serial of the day cannot be superseded
+        let own_key = storage.own_key().clone();
+        let last = UnsignedOpinion {
+            serial: u8::MAX,
+            ..Default::default()
+        }
+        .sign_using(&statement, &own_key.key);
+        block_on(storage.persist_opinion(last, &id)).unwrap();
+        assert!(block_on(storage.sign_own_opinion(&id, &statement, UnsignedOpinion::default()))
+            .is_err());
+        let opinions = block_on(storage.list_opinions_on(id)).unwrap();
+        assert_eq!(opinions[0].data.data.serial, u8::MAX);
+    }
+
+    #[test]
+    fn known_peers() {
+        let storage = block_on(Storage::temporary());
+        let peer_id = libp2p::PeerId::random();
+        let first: libp2p::Multiaddr = "/ip4/192.0.2.1/tcp/10000".parse().unwrap();
+        let second: libp2p::Multiaddr = "/ip6/2001:db8::1/tcp/10000".parse().unwrap();
+        block_on(storage.remember_peer(&peer_id, std::slice::from_ref(&first))).unwrap();
+        block_on(storage.remember_peer(&peer_id, &[second.clone(), first.clone()])).unwrap();
+        block_on(storage.peer_synced(&peer_id)).unwrap();
+        let peers = block_on(storage.list_peers()).unwrap();
+        let known = peers.iter().find(|p| p.peer_id == peer_id).unwrap();
+        assert_eq!(known.addresses, [second, first]);
+        assert_eq!(known.synced_rounds, 1);
+        assert!(known.last_synced.is_some());
+
+        // pretend the peer was last seen two days ago
+        block_on(
+            sqlx::query("update peer set last_seen = last_seen - 172800 where peer_id = ?")
+                .bind(peer_id.to_string())
+                .execute(&storage.pool),
+        )
+        .unwrap();
+        block_on(storage.expire_peers(std::time::Duration::from_secs(86400))).unwrap();
+        let peers = block_on(storage.list_peers()).unwrap();
+        assert!(!peers.iter().any(|p| p.peer_id == peer_id));
+    }
+
+    #[test]
+    fn listings() {
+        let storage = block_on(Storage::temporary());
+        let templates = storage.templates();
+        assert!(templates
+            .iter()
+            .any(|(_, t)| t.to_string() == "trusts(Signer,Signer)"));
+        assert!(templates.windows(2).all(|w| w[0].0 < w[1].0));
+        let own = match &storage.own_key().signer {
+            Entity::Signer(own) => own.clone(),
+            _ => panic!("own key is not a signer"),
+        };
+        let signers = storage.signers();
+        let (_, _, trust) = signers.iter().find(|(_, s, _)| *s == own).unwrap();
+        assert_eq!(*trust, Some(1.0));
+
+        let stats = block_on(storage.stats()).unwrap();
+        assert_eq!(stats.templates, templates.len());
+        assert_eq!(stats.signers, signers.len());
+        assert!(stats.statements >= stats.templates as i64);
+        assert!(stats.opinions >= 3);
+    }
+
     #[test]
     fn test_sqlite() {
         use sqlx::{sqlite::SqliteConnection, Connection};
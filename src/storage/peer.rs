@@ -0,0 +1,111 @@
+use std::{str::FromStr, time::Duration};
+
+use chrono::Utc;
+use libp2p::{Multiaddr, PeerId};
+use log::warn;
+use sqlx::Error;
+
+use super::{Storage, DB};
+
+/// at most this many addresses are remembered per peer, the most recently seen first
+const MAX_ADDRESSES: usize = 8;
+
+/// A peer we have been connected to, as remembered across restarts
+#[derive(Clone, Debug)]
+pub struct KnownPeer {
+    pub peer_id: PeerId,
+    pub addresses: Vec<Multiaddr>,
+    pub last_seen: i64,           // seconds since UNIX epoch
+    pub synced_rounds: u32,       // completed catch-up rounds
+    pub last_synced: Option<i64>, // end of the last completed catch-up round
+}
+
+impl Storage {
+    /// Note that a peer is connected, together with addresses under which it can be dialed
+    pub async fn remember_peer(
+        &self,
+        peer_id: &PeerId,
+        addresses: &[Multiaddr],
+    ) -> Result<(), Error> {
+        let mut tx = self.pool.begin().await?;
+        let known = sqlx::query_as::<DB, (String,)>("select addresses from peer where peer_id = ?")
+            .bind(peer_id.to_string())
+            .fetch_optional(&mut tx)
+            .await?;
+        let mut merged = addresses.to_vec();
+        if let Some((known,)) = known {
+            for address in parse_addresses(&known) {
+                if !merged.contains(&address) {
+                    merged.push(address);
+                }
+            }
+        }
+        merged.truncate(MAX_ADDRESSES);
+        let addresses = merged.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        sqlx::query(
+            "insert into peer(peer_id, addresses, last_seen) values(?, ?, ?)
+            on conflict(peer_id) do update set addresses = excluded.addresses, last_seen = excluded.last_seen",
+        )
+        .bind(peer_id.to_string())
+        .bind(addresses.join(" "))
+        .bind(Utc::now().timestamp())
+        .execute(&mut tx)
+        .await?;
+        tx.commit().await
+    }
+
+    /// Note that a catch-up round with a peer was completed
+    pub async fn peer_synced(&self, peer_id: &PeerId) -> Result<(), Error> {
+        let now = Utc::now().timestamp();
+        sqlx::query(
+            "update peer set synced_rounds = synced_rounds + 1, last_synced = ?, last_seen = ?
+            where peer_id = ?",
+        )
+        .bind(now)
+        .bind(now)
+        .bind(peer_id.to_string())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// List known peers, those we have recently synchronized with first
+    pub async fn list_peers(&self) -> Result<Vec<KnownPeer>, Error> {
+        let rows = sqlx::query_as::<DB, (String, String, i64, u32, Option<i64>)>(
+            "select peer_id, addresses, last_seen, synced_rounds, last_synced from peer
+            order by last_synced is null, last_synced desc, last_seen desc",
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut result = vec![];
+        for (peer_id, addresses, last_seen, synced_rounds, last_synced) in rows {
+            match PeerId::from_str(&peer_id) {
+                Ok(peer_id) => result.push(KnownPeer {
+                    peer_id,
+                    addresses: parse_addresses(&addresses),
+                    last_seen,
+                    synced_rounds,
+                    last_synced,
+                }),
+                Err(_) => warn!("invalid peer id {} in database", peer_id),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Forget peers which have not been seen for `max_age`. Returns the number of expired peers
+    pub async fn expire_peers(&self, max_age: Duration) -> Result<u64, Error> {
+        let result = sqlx::query("delete from peer where last_seen < ?")
+            .bind(Utc::now().timestamp() - max_age.as_secs() as i64)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+fn parse_addresses(addresses: &str) -> Vec<Multiaddr> {
+    addresses
+        .split_ascii_whitespace()
+        .filter_map(|a| Multiaddr::from_str(a).ok())
+        .collect()
+}
@@ -53,6 +53,7 @@ pub trait Get<T> {
     async fn get(&self, id: Id<T>) -> Result<Option<Persistent<T>>, sqlx::Error>;
 
     /// get all records
+    #[allow(dead_code)]
     async fn get_all(&self) -> Result<Vec<Persistent<T>>, sqlx::Error>;
 }
 
@@ -103,7 +104,13 @@ impl<T> Eq for Id<T> {}
 
 impl<T> PartialOrd for Id<T> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        self.id.partial_cmp(&other.id)
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Id<T> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.id.cmp(&other.id)
     }
 }
 
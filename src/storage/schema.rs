@@ -1,8 +1,6 @@
-use std::str::FromStr;
-
 use sqlx::{FromRow, Row};
 
-use crate::model::{Entity, UnsignedOpinion};
+use crate::model::{Entity, SignatureFormat, UnsignedOpinion};
 
 use super::{Date, Id, Opinion, Persistent, RowType, Statement, Storage, Get};
 
@@ -12,10 +10,6 @@ use super::{Date, Id, Opinion, Persistent, RowType, Statement, Storage, Get};
 pub struct DbStatement {
     pub id: Id<Statement>,
     pub name: String,
-    pub entity_1: String,
-    pub entity_2: Option<String>,
-    pub entity_3: Option<String>,
-    pub entity_4: Option<String>,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -27,30 +21,37 @@ pub struct DbOpinion {
     pub valid: u16,
     pub serial: u8,
     pub certainty: i8,
-    pub comment: String,
+    pub comment: Option<String>,
     pub signature: String,
+    pub format: u8,
+}
+
+/// A statement joined with one of its entities
+#[derive(Debug)]
+pub struct DbStatementWithEntity {
+    pub statement: DbStatement,
+    pub entity: String,
 }
 
+/// A statement joined with one of its opinions and one of its entities
 #[derive(Debug)]
 pub struct DbStatementWithOpinion {
     pub statement: DbStatement,
     pub opinion: DbOpinion,
+    pub entity: String,
 }
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct DbPrivateKey {
     pub signer_id: Id<Statement>,
     pub key: String,
+    pub algorithm: String,
 }
 
 impl RowType for DbStatement {
     const TABLE: &'static str = "statement";
     const COLUMNS: &'static str = "statement.id,
-        statement.name,
-        statement.entity_1,
-        statement.entity_2,
-        statement.entity_3,
-        statement.entity_4";
+        statement.name";
 }
 
 impl RowType for DbOpinion {
@@ -63,18 +64,44 @@ impl RowType for DbOpinion {
         opinion.serial,
         opinion.certainty,
         opinion.comment,
-        opinion.signature";
+        opinion.signature,
+        opinion.format";
+}
+
+impl RowType for DbStatementWithEntity {
+    const TABLE: &'static str =
+        "statement join statement_entity on statement.id = statement_entity.statement_id";
+    const COLUMNS: &'static str = "statement.id,
+        statement.name,
+        statement_entity.entity";
+}
+
+impl<'r, R: Row + Send> FromRow<'r, R> for DbStatementWithEntity
+where
+    i64: sqlx::Type<<R as Row>::Database>,
+    i64: sqlx::Decode<'r, <R as Row>::Database>,
+    String: sqlx::Type<<R as Row>::Database>,
+    String: sqlx::Decode<'r, <R as Row>::Database>,
+    usize: sqlx::ColumnIndex<R>,
+{
+    fn from_row(row: &'r R) -> Result<Self, sqlx::Error> {
+        let statement = DbStatement {
+            id: row.get(0),
+            name: row.get(1),
+        };
+        Ok(Self {
+            statement,
+            entity: row.get(2),
+        })
+    }
 }
 
 // this is ugly as the columns are repeated, I can't currently compute them at compile time
 impl RowType for DbStatementWithOpinion {
-    const TABLE: &'static str = "statement join opinion on statement.id = opinion.statement_id";
+    const TABLE: &'static str = "statement join opinion on statement.id = opinion.statement_id
+        join statement_entity on statement.id = statement_entity.statement_id";
     const COLUMNS: &'static str = "statement.id,
         statement.name,
-        statement.entity_1,
-        statement.entity_2,
-        statement.entity_3,
-        statement.entity_4,
         opinion.id,
         opinion.statement_id,
         opinion.signer_id,
@@ -83,7 +110,9 @@ impl RowType for DbStatementWithOpinion {
         opinion.serial,
         opinion.certainty,
         opinion.comment,
-        opinion.signature";
+        opinion.signature,
+        opinion.format,
+        statement_entity.entity";
 }
 
 impl<'r, R: Row + Send> FromRow<'r, R> for DbStatementWithOpinion
@@ -106,67 +135,49 @@ where
         let statement = DbStatement {
             id: row.get(0),
             name: row.get(1),
-            entity_1: row.get(2),
-            entity_2: row.get(3),
-            entity_3: row.get(4),
-            entity_4: row.get(5),
         };
         let opinion = DbOpinion {
-            id: row.get(6),
-            statement_id: row.get(7),
-            signer_id: row.get(8),
-            date: row.get(9),
-            valid: row.get(10),
-            serial: row.get(11),
-            certainty: row.get(12),
-            comment: row.get(13),
-            signature: row.get(14),
+            id: row.get(2),
+            statement_id: row.get(3),
+            signer_id: row.get(4),
+            date: row.get(5),
+            valid: row.get(6),
+            serial: row.get(7),
+            certainty: row.get(8),
+            comment: row.get(9),
+            signature: row.get(10),
+            format: row.get(11),
         };
-        Ok(Self { statement, opinion })
+        Ok(Self {
+            statement,
+            opinion,
+            entity: row.get(12),
+        })
     }
 }
 
 impl RowType for DbPrivateKey {
     const TABLE: &'static str = "private_key";
     const COLUMNS: &'static str = "private_key.signer_id,
-        private_key.key";
-}
-
-impl From<DbStatement> for Statement {
-    fn from(row: DbStatement) -> Statement {
-        let mut entities = vec![Entity::from_str(&row.entity_1.as_str()).unwrap()];
-        if let Some(entity) = row.entity_2 {
-            entities.push(Entity::from_str(&entity.as_str()).unwrap())
-        }
-        if let Some(entity) = row.entity_3 {
-            entities.push(Entity::from_str(&entity.as_str()).unwrap())
-        }
-        if let Some(entity) = row.entity_4 {
-            entities.push(Entity::from_str(&entity.as_str()).unwrap())
-        }
-        Statement {
-            name: row.name,
-            entities,
-        }
-    }
-}
-
-impl From<DbStatement> for Persistent<Statement> {
-    fn from(row: DbStatement) -> Persistent<Statement> {
-        let id = row.id;
-        Persistent {
-            id,
-            data: row.into(),
-        }
-    }
+        private_key.key,
+        private_key.algorithm";
 }
 
 impl Opinion {
     pub async fn from_using_storage(row: DbOpinion, storage: &Storage) -> Opinion {
-        let signer_statement: Persistent<Statement> = storage.get(row.signer_id).await.expect("could find signer").unwrap();
-        let signer = match &signer_statement.entities[0] {
-            Entity::Signer(key) => key,
-            _ => { panic!("expected signer")},
+        let signer = match storage.signers.get(&row.signer_id) {
+            Some(signer) => signer.clone(),
+            None => {
+                let signer_statement: Persistent<Statement> = storage
+                    .get(row.signer_id)
+                    .await
+                    .expect("could find signer")
+                    .unwrap();
+                match &signer_statement.entities[0] {
+                    Entity::Signer(key) => key.clone(),
+                    _ => panic!("expected signer"),
+                }
+            }
         };
         Opinion {
             data: UnsignedOpinion {
@@ -174,10 +185,11 @@ impl Opinion {
                 valid: row.valid,
                 serial: row.serial,
                 certainty: row.certainty,
-                comment: row.comment,
+                comment: row.comment.unwrap_or_default(),
             },
-            signer: signer.clone(),
+            signer,
             signature: base64::decode(row.signature).unwrap(),
+            format: SignatureFormat::from_i64(row.format.into()).expect("known signature format"),
         }
     }
 }
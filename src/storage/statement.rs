@@ -7,7 +7,8 @@ use sqlx::Error;
 use crate::model::{Entity, Statement};
 
 use super::{
-    Convert, DbStatement, Get, Id, PersistResult, Persistent, Repository, RowType, Storage, DB,
+    Convert, DbStatement, DbStatementWithEntity, Get, Id, PersistResult, Persistent, Repository,
+    RowType, Storage, DB,
 };
 
 #[async_trait]
@@ -19,43 +20,17 @@ impl Repository<Statement> for Storage {
             return Err(Error::RowNotFound);
         }
 
-        let entity_1 = statement.entities[0].to_string();
-        let (cidr_min, cidr_max) = statement.entities[0].cidr_minmax();
-        let entity_2 = statement.entities.get(1).and_then(|e| Some(e.to_string()));
-        let entity_3 = statement.entities.get(2).and_then(|e| Some(e.to_string()));
-        let entity_4 = statement.entities.get(3).and_then(|e| Some(e.to_string()));
-
         // first try to find existing statement
-        let result = self
-            .try_select_statement(&statement.name, &entity_1, &entity_2, &entity_3, &entity_4)
-            .await?;
+        let result = self.try_select_statement(&statement).await?;
 
         let result = match result {
             Some(id) => PersistResult::old(id, statement),
             None => {
-                let insert_result = self
-                    .try_insert_statement(
-                        &statement.name,
-                        &entity_1,
-                        &entity_2,
-                        &entity_3,
-                        &entity_4,
-                        &cidr_min,
-                        &cidr_max,
-                    )
-                    .await;
+                let insert_result = self.try_insert_statement(&statement).await;
                 match insert_result {
                     Ok(id) => PersistResult::new(id, statement),
                     Err(_) => {
-                        let result = self
-                            .try_select_statement(
-                                &statement.name,
-                                &entity_1,
-                                &entity_2,
-                                &entity_3,
-                                &entity_4,
-                            )
-                            .await?;
+                        let result = self.try_select_statement(&statement).await?;
                         match result {
                             Some(id) => PersistResult::old(id, statement),
                             None => panic!("could not insert statement"),
@@ -82,19 +57,23 @@ impl Repository<Statement> for Storage {
 impl Get<Statement> for Storage {
     async fn get(&self, id: Id<Statement>) -> Result<Option<Persistent<Statement>>, Error> {
         debug!("DB: getting {} with id {}", DbStatement::TABLE, id);
-        match sqlx::query_as::<DB, DbStatement>(&format!(
-            "select {} from {} where id = ?",
-            DbStatement::COLUMNS,
-            DbStatement::TABLE
+        match sqlx::query_as::<DB, DbStatementWithEntity>(&format!(
+            "select {} from {} where statement.id = ? order by statement_entity.position",
+            DbStatementWithEntity::COLUMNS,
+            DbStatementWithEntity::TABLE
         ))
         .bind(id)
-        .fetch_one(&self.pool)
+        .fetch_all(&self.pool)
         .await
         {
-            Ok(db_row) => {
-                debug!("got DB row {:?}", db_row);
-                let row = self.convert(db_row).await?;
-                return Ok(Some(row));
+            Ok(db_rows) if !db_rows.is_empty() => {
+                debug!("got DB rows {:?}", db_rows);
+                let row = self.convert(db_rows).await?.pop();
+                return Ok(row);
+            }
+            Ok(_) => {
+                error!("no {} with id {}", DbStatement::TABLE, id);
+                Ok(None)
             }
             Err(e) => {
                 error!(
@@ -109,40 +88,38 @@ impl Get<Statement> for Storage {
     }
 
     async fn get_all(&self) -> Result<Vec<Persistent<Statement>>, Error> {
-        let rows = sqlx::query_as::<DB, DbStatement>(&format!(
-            "select {} from {}",
-            DbStatement::COLUMNS,
-            DbStatement::TABLE
+        let rows = sqlx::query_as::<DB, DbStatementWithEntity>(&format!(
+            "select {} from {} order by statement.id, statement_entity.position",
+            DbStatementWithEntity::COLUMNS,
+            DbStatementWithEntity::TABLE
         ))
         .fetch_all(&self.pool)
         .await?;
-        let mut list = vec![];
-        for db_row in rows {
-            list.push(self.convert(db_row).await?)
-        }
-        Ok(list)
+        self.convert(rows).await
     }
 }
 
+// group rows ordered by statement id and entity position into statements
 #[async_trait]
-impl Convert<DbStatement, Persistent<Statement>> for Storage {
-    async fn convert(&self, from: DbStatement) -> Result<Persistent<Statement>, Error> {
-        let mut entities = vec![Entity::from_str(from.entity_1.as_str()).unwrap()];
-        if let Some(entity) = from.entity_2 {
-            entities.push(Entity::from_str(entity.as_str()).unwrap())
-        }
-        if let Some(entity) = from.entity_3 {
-            entities.push(Entity::from_str(entity.as_str()).unwrap())
-        }
-        if let Some(entity) = from.entity_4 {
-            entities.push(Entity::from_str(entity.as_str()).unwrap())
+impl Convert<Vec<DbStatementWithEntity>, Vec<Persistent<Statement>>> for Storage {
+    async fn convert(
+        &self,
+        from: Vec<DbStatementWithEntity>,
+    ) -> Result<Vec<Persistent<Statement>>, Error> {
+        let mut statements: Vec<Persistent<Statement>> = vec![];
+        for row in from {
+            let entity = Entity::from_str(&row.entity).unwrap();
+            match statements.last_mut() {
+                Some(last) if last.id == row.statement.id => last.data.entities.push(entity),
+                _ => statements.push(Persistent {
+                    id: row.statement.id,
+                    data: Statement {
+                        name: row.statement.name,
+                        entities: vec![entity],
+                    },
+                }),
+            }
         }
-        Ok(Persistent {
-            id: from.id,
-            data: Statement {
-                name: from.name.clone(),
-                entities,
-            },
-        })
+        Ok(statements)
     }
 }
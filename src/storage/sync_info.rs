@@ -1,11 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 use libp2p::multihash::{Sha2_256, StatefulHasher};
 use serde::{Deserialize, Serialize};
 
 use crate::model::Date;
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+/// ranges with at most this many opinions are transferred as a list of signatures
+const ITEMS_LIMIT: usize = 32;
+/// larger ranges are split into this many subranges
+const BRANCHES: usize = 16;
+
+/// Summary of a set of opinions: their number and a hash over their signatures
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct SyncInfo {
     count: usize,
     hash: String,
@@ -17,6 +23,29 @@ pub struct SyncInfos {
     pub infos: HashMap<String, SyncInfo>,
 }
 
+/// A range of opinion signatures from `lower` (inclusive) to `upper` (exclusive, None for no upper bound),
+/// with either a summary or the complete list of the signatures in it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SyncRange {
+    pub lower: String,
+    pub upper: Option<String>,
+    pub content: RangeContent,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum RangeContent {
+    Fingerprint(SyncInfo),
+    Items(Vec<String>),
+}
+
+/// The result of comparing a peer's ranges with our own signatures
+#[derive(Debug, Default)]
+pub struct Reconciliation {
+    pub ranges: Vec<SyncRange>, // ranges which are still different and must be examined by the peer
+    pub send: Vec<String>,      // signatures of opinions the peer does not have
+    pub want: Vec<String>,      // signatures of opinions we do not have
+}
+
 impl SyncInfo {
     pub fn new(data: Vec<Vec<u8>>) -> Self {
         let count = data.len();
@@ -28,8 +57,180 @@ impl SyncInfo {
         Self { count, hash }
     }
 
-    /// Does the info in `other` suggest that we should update our data?
-    pub fn suggests_update(&self, other: &Self) -> bool {
-        self.count < other.count || (self.count == other.count && self.hash != other.hash)
+    /// Summary of a sorted list of base64 encoded signatures
+    pub fn of(signatures: &[String]) -> Self {
+        Self::new(
+            signatures
+                .iter()
+                .map(|s| base64::decode(s).unwrap_or_else(|_| s.as_bytes().to_vec()))
+                .collect(),
+        )
+    }
+}
+
+impl SyncRange {
+    /// The part of the sorted list `signatures` within this range
+    fn select<'a>(&self, signatures: &'a [String]) -> &'a [String] {
+        let start = signatures.partition_point(|s| s < &self.lower);
+        let end = match &self.upper {
+            Some(upper) => signatures.partition_point(|s| s < upper),
+            None => signatures.len(),
+        };
+        &signatures[start..end.max(start)]
+    }
+}
+
+/// Describe our own signatures between `lower` and `upper` for the peer: small ranges as a list of signatures,
+/// larger ones as summaries of `BRANCHES` subranges
+fn describe(lower: &str, upper: &Option<String>, signatures: &[String]) -> Vec<SyncRange> {
+    if signatures.len() <= ITEMS_LIMIT {
+        return vec![SyncRange {
+            lower: lower.to_string(),
+            upper: upper.clone(),
+            content: RangeContent::Items(signatures.to_vec()),
+        }];
+    }
+    let mut ranges = vec![];
+    let mut start = 0;
+    let mut bound = lower.to_string();
+    for branch in 1..=BRANCHES {
+        let end = signatures.len() * branch / BRANCHES;
+        let next = match branch {
+            BRANCHES => upper.clone(),
+            _ => Some(signatures[end].clone()),
+        };
+        ranges.push(SyncRange {
+            lower: bound,
+            upper: next.clone(),
+            content: RangeContent::Fingerprint(SyncInfo::of(&signatures[start..end])),
+        });
+        bound = next.unwrap_or_default();
+        start = end;
+    }
+    ranges
+}
+
+/// The first step of a reconciliation, describing all of our sorted signatures
+pub fn initial_ranges(signatures: &[String]) -> Vec<SyncRange> {
+    describe("", &None, signatures)
+}
+
+/// Compare the ranges received from a peer with our own sorted signatures.
+/// Matching ranges are done, differing summaries are split further, and lists of signatures
+/// tell which opinions have to be transferred in either direction.
+pub fn reconcile(signatures: &[String], ranges: &[SyncRange]) -> Reconciliation {
+    let mut result = Reconciliation::default();
+    for range in ranges {
+        let own = range.select(signatures);
+        match &range.content {
+            RangeContent::Fingerprint(info) => {
+                if *info != SyncInfo::of(own) {
+                    result
+                        .ranges
+                        .extend(describe(&range.lower, &range.upper, own));
+                }
+            }
+            RangeContent::Items(items) => {
+                let theirs = items.iter().collect::<BTreeSet<_>>();
+                let ours = own.iter().collect::<BTreeSet<_>>();
+                result
+                    .send
+                    .extend(ours.difference(&theirs).map(|s| s.to_string()));
+                result
+                    .want
+                    .extend(theirs.difference(&ours).map(|s| s.to_string()));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signatures(range: std::ops::Range<u32>) -> Vec<String> {
+        let mut list = range
+            .map(|i| {
+                let mut hasher = Sha2_256::default();
+                hasher.update(&i.to_be_bytes());
+                base64::encode(hasher.finalize())
+            })
+            .collect::<Vec<_>>();
+        list.sort();
+        list
+    }
+
+    // run a reconciliation between two peers, returning what each side sends and the number of steps
+    fn run(a: &[String], b: &[String]) -> (BTreeSet<String>, BTreeSet<String>, usize) {
+        let mut sent_by_a = BTreeSet::new();
+        let mut sent_by_b = BTreeSet::new();
+        let mut ranges = initial_ranges(a);
+        let mut steps = 0;
+        let mut a_turn = false;
+        while !ranges.is_empty() {
+            steps += 1;
+            let (own, sent, other_sent) = match a_turn {
+                true => (a, &mut sent_by_a, &mut sent_by_b),
+                false => (b, &mut sent_by_b, &mut sent_by_a),
+            };
+            let result = reconcile(own, &ranges);
+            sent.extend(result.send);
+            other_sent.extend(result.want);
+            ranges = result.ranges;
+            a_turn = !a_turn;
+        }
+        (sent_by_a, sent_by_b, steps)
+    }
+
+    #[test]
+    fn equal_sets() {
+        let a = signatures(0..1000);
+        let range = SyncRange {
+            lower: String::new(),
+            upper: None,
+            content: RangeContent::Fingerprint(SyncInfo::of(&a)),
+        };
+        let result = reconcile(&a, &[range]);
+        assert!(result.ranges.is_empty());
+        let (sent_by_a, sent_by_b, _) = run(&a, &a);
+        assert!(sent_by_a.is_empty());
+        assert!(sent_by_b.is_empty());
+    }
+
+    #[test]
+    fn small_sets() {
+        let a = signatures(0..10);
+        let b = signatures(5..20);
+        let (sent_by_a, sent_by_b, steps) = run(&a, &b);
+        assert_eq!(steps, 1);
+        assert_eq!(sent_by_a, signatures(0..5).into_iter().collect());
+        assert_eq!(sent_by_b, signatures(10..20).into_iter().collect());
+    }
+
+    #[test]
+    fn large_sets() {
+        let common = signatures(0..20000);
+        let only_a = signatures(30000..30003);
+        let only_b = signatures(40000..40005);
+        let mut a = common.iter().chain(only_a.iter()).cloned().collect::<Vec<_>>();
+        let mut b = common.iter().chain(only_b.iter()).cloned().collect::<Vec<_>>();
+        a.sort();
+        b.sort();
+        let (sent_by_a, sent_by_b, steps) = run(&a, &b);
+        assert_eq!(sent_by_a, only_a.into_iter().collect());
+        assert_eq!(sent_by_b, only_b.into_iter().collect());
+        assert!(steps < 6);
+    }
+
+    #[test]
+    fn empty_side() {
+        let a = signatures(0..100);
+        let (sent_by_a, sent_by_b, _) = run(&a, &[]);
+        assert_eq!(sent_by_a.len(), 100);
+        assert!(sent_by_b.is_empty());
+        let (sent_by_a, sent_by_b, _) = run(&[], &a);
+        assert!(sent_by_a.is_empty());
+        assert_eq!(sent_by_b.len(), 100);
     }
 }